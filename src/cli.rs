@@ -0,0 +1,148 @@
+use clap::{value_parser, Arg, ArgAction, ArgMatches};
+use std::path::PathBuf;
+
+pub struct Cli {
+    pub profile: Option<String>,
+    pub command: CliCommand,
+}
+
+pub enum CliCommand {
+    Ask {
+        /// Falls back to `defaults.repo` from the config when not given.
+        repo: Option<String>,
+        /// Falls back to `defaults.rev` from the config when not given.
+        rev: Option<String>,
+        local: Option<PathBuf>,
+        question: Option<String>,
+        interactive: bool,
+    },
+    Resume {
+        id: String,
+        local: Option<PathBuf>,
+        question: Option<String>,
+        interactive: bool,
+    },
+    ListSessions,
+}
+
+pub fn build() -> clap::Command {
+    clap::Command::new("sourcemapt")
+        .about("Answers questions about a codebase by letting a model browse it")
+        .subcommand_required(true)
+        .arg_required_else_help(true)
+        .arg(
+            Arg::new("profile")
+                .long("profile")
+                .short('p')
+                .help("Apply the [profile.<NAME>] section of the configuration file")
+                .value_name("NAME")
+                .global(true),
+        )
+        .subcommand(
+            clap::Command::new("ask")
+                .about("Ask a question about a repository")
+                .arg(
+                    Arg::new("repo")
+                        .long("repo")
+                        .short('r')
+                        .help("Repository to explore, e.g. github.com/kubernetes/kubernetes"),
+                )
+                .arg(
+                    Arg::new("rev")
+                        .long("rev")
+                        .help("Revision (branch, tag or commit) to read from"),
+                )
+                .arg(
+                    Arg::new("local")
+                        .long("local")
+                        .help("Explore a git repository on disk instead of Sourcegraph")
+                        .value_name("PATH")
+                        .value_parser(value_parser!(PathBuf)),
+                )
+                .arg(
+                    Arg::new("interactive")
+                        .long("interactive")
+                        .short('i')
+                        .help("Keep the session open and read follow-up questions from stdin")
+                        .action(ArgAction::SetTrue),
+                )
+                .arg(
+                    Arg::new("question")
+                        .help("The question to answer")
+                        .required_unless_present("interactive"),
+                ),
+        )
+        .subcommand(
+            clap::Command::new("resume")
+                .about("Continue a saved session")
+                .arg(
+                    Arg::new("id")
+                        .help("Session ID, as shown by `sessions list`")
+                        .required(true),
+                )
+                .arg(
+                    Arg::new("local")
+                        .long("local")
+                        .help("Explore a git repository on disk instead of Sourcegraph")
+                        .value_name("PATH")
+                        .value_parser(value_parser!(PathBuf)),
+                )
+                .arg(
+                    Arg::new("interactive")
+                        .long("interactive")
+                        .short('i')
+                        .help("Keep the session open and read follow-up questions from stdin")
+                        .action(ArgAction::SetTrue),
+                )
+                .arg(
+                    Arg::new("question")
+                        .help("A follow-up question; without one, an unfinished investigation is continued"),
+                ),
+        )
+        .subcommand(
+            clap::Command::new("sessions")
+                .about("Manage saved sessions")
+                .subcommand_required(true)
+                .subcommand(clap::Command::new("list").about("List saved sessions")),
+        )
+}
+
+pub fn parse() -> Cli {
+    from_matches(&build().get_matches())
+}
+
+pub fn from_matches(matches: &ArgMatches) -> Cli {
+    let (name, sub) = matches
+        .subcommand()
+        .expect("subcommand_required prevents this");
+
+    // Global args are propagated down, so the subcommand sees --profile wherever it was given
+    let profile = get_string(sub, "profile");
+
+    let command = match name {
+        "ask" => CliCommand::Ask {
+            repo: get_string(sub, "repo"),
+            rev: get_string(sub, "rev"),
+            local: sub.get_one::<PathBuf>("local").cloned(),
+            question: get_string(sub, "question"),
+            interactive: sub.get_flag("interactive"),
+        },
+        "resume" => CliCommand::Resume {
+            id: get_string(sub, "id").unwrap_or_default(),
+            local: sub.get_one::<PathBuf>("local").cloned(),
+            question: get_string(sub, "question"),
+            interactive: sub.get_flag("interactive"),
+        },
+        "sessions" => match sub.subcommand() {
+            Some(("list", _)) => CliCommand::ListSessions,
+            _ => unreachable!("subcommand_required prevents this"),
+        },
+        _ => unreachable!("unknown subcommand {}", name),
+    };
+
+    Cli { profile, command }
+}
+
+fn get_string(matches: &ArgMatches, id: &str) -> Option<String> {
+    matches.get_one::<String>(id).cloned()
+}
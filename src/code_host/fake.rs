@@ -0,0 +1,157 @@
+use crate::code_host::CodeHost;
+use crate::error::SourcemaptError;
+use crate::sourcegraph::definition_and_hover::GetDefinitionResult;
+use crate::sourcegraph::file_content::GetFileContentResult;
+use crate::sourcegraph::implementations::GetImplementationsResult;
+use crate::sourcegraph::list_dir::{DirEntry, ListDirResult};
+use crate::sourcegraph::references::GetReferencesResult;
+use crate::sourcegraph::search_files::SearchFilesResult;
+use crate::sourcegraph::symbols::GetSymbolsResult;
+use async_trait::async_trait;
+use futures::channel::oneshot;
+use std::cell::RefCell;
+use std::rc::Rc;
+
+pub const FILE_LINES: usize = 10;
+
+/// A code host where every file exists and has `FILE_LINES` lines naming its path and line
+/// number, like "pkg/kiwi.go line 3", and every directory holds a single `kiwi.go`. Only reading
+/// files and listing directories is supported.
+#[derive(Default)]
+pub struct FakeCodeHost {
+    calls: Rc<RefCell<Vec<String>>>,
+    /// A file whose read only finishes once the paired receiver fires.
+    held: RefCell<Option<(String, oneshot::Receiver<()>)>>,
+    /// A file whose read releases the held one.
+    release: RefCell<Option<(String, oneshot::Sender<()>)>>,
+}
+
+impl FakeCodeHost {
+    /// Makes reading `path` wait until `until` has been read, so the two finish in that order
+    /// whatever order they were started in.
+    pub fn hold(self, path: &str, until: &str) -> Self {
+        let (sender, receiver) = oneshot::channel();
+        *self.held.borrow_mut() = Some((path.to_owned(), receiver));
+        *self.release.borrow_mut() = Some((until.to_owned(), sender));
+        self
+    }
+
+    /// Every call made so far, like "read pkg/kiwi.go" or "list pkg", in the order they finished.
+    /// Stays readable after the host is handed to `Sourcemapt`.
+    pub fn calls(&self) -> Rc<RefCell<Vec<String>>> {
+        self.calls.clone()
+    }
+}
+
+fn unsupported(operation: &str) -> SourcemaptError {
+    SourcemaptError::NotFound(format!("the fake code host doesn't support {}", operation))
+}
+
+#[async_trait(?Send)]
+impl CodeHost for FakeCodeHost {
+    async fn resolve_revision(&self, _repo: &str, rev: &str) -> Result<String, SourcemaptError> {
+        Ok(rev.to_owned())
+    }
+
+    async fn search_files(
+        &self,
+        _repo: &str,
+        _rev: &str,
+        _search_terms: &[String],
+    ) -> Result<SearchFilesResult, SourcemaptError> {
+        Err(unsupported("searching"))
+    }
+
+    async fn get_file_content(
+        &self,
+        _repo: &str,
+        _rev: &str,
+        path: &str,
+    ) -> Result<GetFileContentResult, SourcemaptError> {
+        let held = self.held.borrow_mut().take();
+        match held {
+            Some((held, receiver)) if held == path => {
+                let _ = receiver.await;
+            }
+            other => *self.held.borrow_mut() = other,
+        }
+
+        let content = (0..FILE_LINES)
+            .map(|i| format!("{} line {}", path, i))
+            .collect::<Vec<String>>()
+            .join("\n");
+
+        self.calls.borrow_mut().push(format!("read {}", path));
+        let release = self.release.borrow_mut().take();
+        match release {
+            Some((until, sender)) if until == path => {
+                let _ = sender.send(());
+            }
+            other => *self.release.borrow_mut() = other,
+        }
+        Ok(GetFileContentResult { content })
+    }
+
+    async fn get_definition(
+        &self,
+        _repo: &str,
+        _rev: &str,
+        _path: &str,
+        _line: u32,
+        _char: u32,
+    ) -> Result<GetDefinitionResult, SourcemaptError> {
+        Err(unsupported("definitions"))
+    }
+
+    async fn get_references(
+        &self,
+        _repo: &str,
+        _rev: &str,
+        _path: &str,
+        _line: u32,
+        _char: u32,
+        _after: Option<&str>,
+    ) -> Result<GetReferencesResult, SourcemaptError> {
+        Err(unsupported("references"))
+    }
+
+    async fn get_implementations(
+        &self,
+        _repo: &str,
+        _rev: &str,
+        _path: &str,
+        _line: u32,
+        _char: u32,
+        _after: Option<&str>,
+    ) -> Result<GetImplementationsResult, SourcemaptError> {
+        Err(unsupported("implementations"))
+    }
+
+    async fn get_symbols(
+        &self,
+        _repo: &str,
+        _rev: &str,
+        _query: &str,
+        _kind: Option<&str>,
+    ) -> Result<GetSymbolsResult, SourcemaptError> {
+        Err(unsupported("symbols"))
+    }
+
+    async fn list_dir(
+        &self,
+        _repo: &str,
+        _rev: &str,
+        path: &str,
+        _depth: usize,
+    ) -> Result<ListDirResult, SourcemaptError> {
+        self.calls.borrow_mut().push(format!("list {}", path));
+        Ok(ListDirResult {
+            entries: vec![DirEntry {
+                path: format!("{}/kiwi.go", path),
+                kind: "file",
+                size: Some(100),
+            }],
+            truncated: false,
+        })
+    }
+}
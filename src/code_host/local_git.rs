@@ -0,0 +1,776 @@
+use crate::code_host::CodeHost;
+use crate::error::SourcemaptError;
+use crate::sourcegraph::definition_and_hover::{
+    DefinitionNode, GetDefinitionResult, Hover, Range, Resource,
+};
+use crate::sourcegraph::file_content::GetFileContentResult;
+use crate::sourcegraph::implementations::GetImplementationsResult;
+use crate::sourcegraph::list_dir::{DirEntry, ListDirResult, MAX_DIR_ENTRIES};
+use crate::sourcegraph::references::{GetReferencesResult, Reference, REFERENCES_PAGE_SIZE};
+use crate::sourcegraph::search_files::{SearchFilesFileLine, SearchFilesFileMatch, SearchFilesResult};
+use crate::sourcegraph::symbols::{GetSymbolsResult, Symbol, MAX_SYMBOLS};
+use async_trait::async_trait;
+use regex::{Regex, RegexBuilder};
+use std::io::{BufRead, BufReader, Read, Write};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::thread;
+
+const MAX_FILE_MATCHES: usize = 30;
+const MAX_LINE_MATCHES: usize = 5;
+const MAX_BLOB_SIZE: usize = 1024 * 1024;
+
+/// Explores a git repository on the local disk. The working tree is never touched: everything is
+/// read from the object database at the requested revision, so uncommitted changes are invisible.
+pub struct LocalGitHost {
+    path: PathBuf,
+}
+
+impl LocalGitHost {
+    pub fn new(path: PathBuf) -> Self {
+        Self { path }
+    }
+
+    /// Runs a single git command off the async runtime's threads.
+    async fn git(&self, args: Vec<String>) -> Result<Vec<u8>, SourcemaptError> {
+        let path = self.path.clone();
+
+        tokio::task::spawn_blocking(move || {
+            let args = args.iter().map(|v| v.as_str()).collect::<Vec<&str>>();
+            git(&path, &args)
+        })
+            .await?
+    }
+
+    /// The identifier at `line` and `char` of `path`, and the column it starts at. `None` when the
+    /// cursor isn't on an identifier.
+    async fn identifier_under_cursor(
+        &self,
+        repo: &str,
+        rev: &str,
+        path: &str,
+        line: u32,
+        char: u32,
+    ) -> Result<Option<(String, usize)>, SourcemaptError> {
+        let content = self.get_file_content(repo, rev, path).await?.content;
+
+        Ok(content
+            .lines()
+            .nth(line as usize)
+            .and_then(|v| identifier_at(v, char as usize)))
+    }
+}
+
+#[async_trait(?Send)]
+impl CodeHost for LocalGitHost {
+    async fn resolve_revision(&self, _repo: &str, rev: &str) -> Result<String, SourcemaptError> {
+        let output = self
+            .git(vec![
+                "rev-parse".to_owned(),
+                "--verify".to_owned(),
+                format!("{}^{{commit}}", rev),
+            ])
+            .await?;
+        Ok(String::from_utf8_lossy(&output).trim().to_owned())
+    }
+
+    async fn search_files(
+        &self,
+        _repo: &str,
+        rev: &str,
+        search_terms: &[String],
+    ) -> Result<SearchFilesResult, SourcemaptError> {
+        let patterns = search_terms
+            .iter()
+            .map(|term| compile_term(term))
+            .collect::<Result<Vec<Regex>, _>>()?;
+
+        let path = self.path.clone();
+        let rev = rev.to_owned();
+
+        let files = tokio::task::spawn_blocking(move || -> Result<_, SourcemaptError> {
+            let mut files = Vec::new();
+
+            walk_blobs(&path, &rev, |file, content| {
+                let lines = content
+                    .lines()
+                    .enumerate()
+                    .filter(|(_, line)| patterns.iter().any(|p| p.is_match(line)))
+                    .take(MAX_LINE_MATCHES)
+                    .map(|(i, line)| SearchFilesFileLine {
+                        line_number: i as u32,
+                        preview: line.to_owned(),
+                    })
+                    .collect::<Vec<_>>();
+
+                if !lines.is_empty() {
+                    files.push(SearchFilesFileMatch {
+                        path: file.to_owned(),
+                        url: format!("file://{}", path.join(file).display()),
+                        lines,
+                    });
+                }
+
+                files.len() < MAX_FILE_MATCHES
+            })?;
+
+            Ok(files)
+        })
+            .await??;
+
+        Ok(SearchFilesResult { files })
+    }
+
+    async fn get_file_content(
+        &self,
+        _repo: &str,
+        rev: &str,
+        path: &str,
+    ) -> Result<GetFileContentResult, SourcemaptError> {
+        let output = self
+            .git(vec!["show".to_owned(), format!("{}:{}", rev, path)])
+            .await?;
+
+        Ok(GetFileContentResult {
+            content: String::from_utf8_lossy(&output).into_owned(),
+        })
+    }
+
+    /// There is no precise code intelligence for a local checkout, so this looks up the
+    /// identifier under the cursor and searches for lines that look like its declaration.
+    async fn get_definition(
+        &self,
+        repo: &str,
+        rev: &str,
+        path: &str,
+        line: u32,
+        char: u32,
+    ) -> Result<GetDefinitionResult, SourcemaptError> {
+        let (identifier, char_start) = match self
+            .identifier_under_cursor(repo, rev, path, line, char)
+            .await?
+        {
+            Some(v) => v,
+            None => {
+                return Ok(GetDefinitionResult {
+                    definitions: Vec::new(),
+                    hover: None,
+                })
+            }
+        };
+
+        let declaration = Regex::new(&format!(
+            r"\b(func|type|fn|struct|enum|trait|class|interface|def|const|var|let|static)\s+(\([^)]*\)\s*)?{}\b",
+            regex::escape(&identifier)
+        ))?;
+
+        let commit_oid = self
+            .git(vec!["rev-parse".to_owned(), rev.to_owned()])
+            .await?;
+        let commit_oid = String::from_utf8_lossy(&commit_oid).trim().to_owned();
+
+        let checkout = self.path.clone();
+        let walk_rev = rev.to_owned();
+        let found = tokio::task::spawn_blocking(move || -> Result<_, SourcemaptError> {
+            let mut found = Vec::new();
+
+            walk_blobs(&checkout, &walk_rev, |file, content| {
+                for (i, text) in content.lines().enumerate() {
+                    if let Some(m) = declaration.find(text) {
+                        found.push((file.to_owned(), i, m.start(), m.end(), text.trim().to_owned()));
+                    }
+                }
+                found.len() < MAX_FILE_MATCHES
+            })?;
+
+            Ok(found)
+        })
+            .await??;
+
+        // The first declaration found stands in for the hover text
+        let hover = found.first().map(|v| Hover {
+            markdown: format!("```\n{}\n```", v.4),
+            range: Range {
+                line_start: line as i64,
+                char_start: char_start as i64,
+                line_end: line as i64,
+                char_end: (char_start + identifier.len()) as i64,
+            },
+        });
+
+        let definitions = found
+            .into_iter()
+            .map(|(file, i, start, end, _)| DefinitionNode {
+                resource: Resource {
+                    path: file,
+                    repo: repo.to_owned(),
+                    commit_oid: commit_oid.clone(),
+                },
+                range: Range {
+                    line_start: i as i64,
+                    char_start: start as i64,
+                    line_end: i as i64,
+                    char_end: end as i64,
+                },
+            })
+            .collect();
+
+        Ok(GetDefinitionResult { definitions, hover })
+    }
+
+    /// Every whole-word occurrence of the identifier under the cursor. The cursor is the number of
+    /// occurrences already returned.
+    async fn get_references(
+        &self,
+        repo: &str,
+        rev: &str,
+        path: &str,
+        line: u32,
+        char: u32,
+        after: Option<&str>,
+    ) -> Result<GetReferencesResult, SourcemaptError> {
+        let identifier = match self
+            .identifier_under_cursor(repo, rev, path, line, char)
+            .await?
+        {
+            Some((identifier, _)) => identifier,
+            None => {
+                return Ok(GetReferencesResult {
+                    references: Vec::new(),
+                    next: None,
+                })
+            }
+        };
+
+        let skip = match after {
+            Some(after) => after.parse::<usize>().map_err(|_| {
+                SourcemaptError::InvalidCommand(format!("invalid cursor: {}", after))
+            })?,
+            None => 0,
+        };
+
+        let pattern = Regex::new(&format!(r"\b{}\b", regex::escape(&identifier)))?;
+
+        let checkout = self.path.clone();
+        let walk_rev = rev.to_owned();
+        let repo = repo.to_owned();
+        let (references, more) = tokio::task::spawn_blocking(move || -> Result<_, SourcemaptError> {
+            let mut seen = 0;
+            let mut references = Vec::new();
+            let mut more = false;
+
+            walk_blobs(&checkout, &walk_rev, |file, content| {
+                for (i, text) in content.lines().enumerate() {
+                    for m in pattern.find_iter(text) {
+                        seen += 1;
+                        if seen <= skip {
+                            continue;
+                        }
+                        if references.len() == REFERENCES_PAGE_SIZE {
+                            more = true;
+                            return false;
+                        }
+                        references.push(Reference {
+                            repo: repo.clone(),
+                            path: file.to_owned(),
+                            line: i as i64,
+                            character: m.start() as i64,
+                            preview: text.trim().to_owned(),
+                        });
+                    }
+                }
+                true
+            })?;
+
+            Ok((references, more))
+        })
+            .await??;
+
+        let next = if more {
+            Some((skip + references.len()).to_string())
+        } else {
+            None
+        };
+
+        Ok(GetReferencesResult { references, next })
+    }
+
+    /// Lines that look like they implement or extend the identifier under the cursor, such as
+    /// `impl Trait for`, `implements Interface` or `extends Base`. Go's implicit interfaces can't
+    /// be found this way. Results aren't paginated.
+    async fn get_implementations(
+        &self,
+        repo: &str,
+        rev: &str,
+        path: &str,
+        line: u32,
+        char: u32,
+        _after: Option<&str>,
+    ) -> Result<GetImplementationsResult, SourcemaptError> {
+        let identifier = match self
+            .identifier_under_cursor(repo, rev, path, line, char)
+            .await?
+        {
+            Some((identifier, _)) => identifier,
+            None => {
+                return Ok(GetImplementationsResult {
+                    implementations: Vec::new(),
+                    next: None,
+                })
+            }
+        };
+
+        let pattern = Regex::new(&format!(
+            r"\bimpl\b[^{{;]*\b{0}\b[^{{;]*\bfor\b|\b(implements|extends)\b[^{{;]*\b{0}\b",
+            regex::escape(&identifier)
+        ))?;
+
+        let checkout = self.path.clone();
+        let walk_rev = rev.to_owned();
+        let repo = repo.to_owned();
+        let implementations = tokio::task::spawn_blocking(move || -> Result<_, SourcemaptError> {
+            let mut implementations = Vec::new();
+
+            walk_blobs(&checkout, &walk_rev, |file, content| {
+                for (i, text) in content.lines().enumerate() {
+                    if let Some(m) = pattern.find(text) {
+                        implementations.push(Reference {
+                            repo: repo.clone(),
+                            path: file.to_owned(),
+                            line: i as i64,
+                            character: m.start() as i64,
+                            preview: text.trim().to_owned(),
+                        });
+                    }
+                }
+                implementations.len() < REFERENCES_PAGE_SIZE
+            })?;
+
+            Ok(implementations)
+        })
+            .await??;
+
+        Ok(GetImplementationsResult {
+            implementations,
+            next: None,
+        })
+    }
+
+    /// Lines that look like declarations, keeping those whose declared name matches `query`. The
+    /// kind is guessed from the declaring keyword.
+    async fn get_symbols(
+        &self,
+        _repo: &str,
+        rev: &str,
+        query: &str,
+        kind: Option<&str>,
+    ) -> Result<GetSymbolsResult, SourcemaptError> {
+        let name_pattern = compile_term(query)?;
+        let declaration = Regex::new(
+            r"\b(func|type|fn|struct|enum|trait|class|interface|def|const|var|static)\s+(\([^)]*\)\s*)?([A-Za-z_]\w*)",
+        )?;
+
+        let checkout = self.path.clone();
+        let rev = rev.to_owned();
+        let kind = kind.map(|v| v.to_lowercase());
+        let symbols = tokio::task::spawn_blocking(move || -> Result<_, SourcemaptError> {
+            let mut symbols = Vec::new();
+
+            walk_blobs(&checkout, &rev, |file, content| {
+                for (i, text) in content.lines().enumerate() {
+                    let captures = match declaration.captures(text) {
+                        Some(v) => v,
+                        None => continue,
+                    };
+
+                    let name = &captures[3];
+                    if !name_pattern.is_match(name) {
+                        continue;
+                    }
+
+                    let symbol_kind = match &captures[1] {
+                        "func" | "fn" | "def" if captures.get(2).is_some() => "method",
+                        "func" | "fn" | "def" => "function",
+                        "type" | "struct" => "struct",
+                        "enum" => "enum",
+                        "trait" | "interface" => "interface",
+                        "class" => "class",
+                        "const" => "constant",
+                        _ => "variable",
+                    };
+                    if kind.as_deref().map_or(false, |v| v != symbol_kind) {
+                        continue;
+                    }
+
+                    let m = captures.get(3).expect("group 3 always participates");
+                    symbols.push(Symbol {
+                        name: name.to_owned(),
+                        kind: symbol_kind.to_owned(),
+                        container: None,
+                        path: file.to_owned(),
+                        line: i as i64,
+                        character: m.start() as i64,
+                    });
+
+                    if symbols.len() == MAX_SYMBOLS {
+                        return false;
+                    }
+                }
+                true
+            })?;
+
+            Ok(symbols)
+        })
+            .await??;
+
+        Ok(GetSymbolsResult { symbols })
+    }
+
+    async fn list_dir(
+        &self,
+        _repo: &str,
+        rev: &str,
+        path: &str,
+        depth: usize,
+    ) -> Result<ListDirResult, SourcemaptError> {
+        let path = path.trim_matches('/').to_owned();
+        let repo_path = self.path.clone();
+        let rev = rev.to_owned();
+
+        tokio::task::spawn_blocking(move || -> Result<_, SourcemaptError> {
+            let mut entries = Vec::new();
+            let mut level = vec![path.clone()];
+
+            for current_depth in 1..=depth {
+                let mut next_level = Vec::new();
+
+                for dir in level {
+                    let children = list_tree(&repo_path, &rev, &dir)?;
+
+                    if current_depth < depth {
+                        next_level.extend(
+                            children
+                                .iter()
+                                .filter(|v| v.kind == "dir")
+                                .map(|v| v.path.clone()),
+                        );
+                    }
+                    entries.extend(children);
+
+                    if entries.len() > MAX_DIR_ENTRIES {
+                        break;
+                    }
+                }
+
+                if entries.len() > MAX_DIR_ENTRIES {
+                    break;
+                }
+                level = next_level;
+            }
+
+            if entries.is_empty() && !path.is_empty() {
+                return Err(SourcemaptError::NotFound(format!("directory {}", path)));
+            }
+
+            let truncated = entries.len() > MAX_DIR_ENTRIES;
+            entries.truncate(MAX_DIR_ENTRIES);
+
+            Ok(ListDirResult { entries, truncated })
+        })
+            .await?
+    }
+}
+
+/// The direct children of the directory at `dir` in the tree at `rev`.
+fn list_tree(path: &Path, rev: &str, dir: &str) -> Result<Vec<DirEntry>, SourcemaptError> {
+    let prefix = format!("{}/", dir);
+
+    let mut args = vec!["ls-tree", "-l", "-z", rev];
+    if !dir.is_empty() {
+        args.extend(["--", prefix.as_str()]);
+    }
+
+    let tree = git(path, &args)?;
+
+    // Each entry is "<mode> <type> <oid> <size>\t<path>", with a size of "-" for trees
+    Ok(tree
+        .split(|b| *b == 0)
+        .filter_map(|entry| {
+            let entry = std::str::from_utf8(entry).ok()?;
+            let (meta, file) = entry.split_once('\t')?;
+            let mut meta = meta.split_whitespace();
+            let (_, kind, _, size) = (meta.next()?, meta.next()?, meta.next()?, meta.next()?);
+            Some(DirEntry {
+                path: file.to_owned(),
+                kind: if kind == "tree" { "dir" } else { "file" },
+                size: size.parse::<i64>().ok(),
+            })
+        })
+        .collect())
+}
+
+/// Search terms are regexes, matched case-insensitively. A term that isn't a valid regex is
+/// searched for literally.
+fn compile_term(term: &str) -> Result<Regex, regex::Error> {
+    RegexBuilder::new(term)
+        .case_insensitive(true)
+        .build()
+        .or_else(|_| {
+            RegexBuilder::new(&regex::escape(term))
+                .case_insensitive(true)
+                .build()
+        })
+}
+
+fn identifier_at(line: &str, char: usize) -> Option<(String, usize)> {
+    let chars = line.chars().collect::<Vec<char>>();
+    let is_ident = |c: &char| c.is_alphanumeric() || *c == '_';
+
+    if char >= chars.len() || !is_ident(&chars[char]) {
+        return None;
+    }
+
+    let mut start = char;
+    while start > 0 && is_ident(&chars[start - 1]) {
+        start -= 1;
+    }
+    let mut end = char;
+    while end < chars.len() && is_ident(&chars[end]) {
+        end += 1;
+    }
+
+    Some((chars[start..end].iter().collect(), start))
+}
+
+/// Git exits with an error for unknown revisions and paths, which are reported as not found. Any
+/// other failure, such as git not being installed or `path` not being a repository, is an I/O
+/// error.
+fn git(path: &Path, args: &[&str]) -> Result<Vec<u8>, SourcemaptError> {
+    // Git's messages are only classified in English
+    let output = Command::new("git")
+        .env("LC_ALL", "C")
+        .arg("-C")
+        .arg(path)
+        .args(args)
+        .output()
+        .map_err(|e| SourcemaptError::Io(format!("failed to run git: {}", e)))?;
+
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        let message = format!("git {} failed: {}", args.join(" "), stderr.trim());
+
+        let lowercase = stderr.to_lowercase();
+        let missing = [
+            "unknown revision",
+            "bad revision",
+            "invalid object name",
+            "not a valid object name",
+            "needed a single revision",
+            "does not exist",
+            "exists on disk, but not in",
+        ];
+        return Err(if missing.iter().any(|v| lowercase.contains(v)) {
+            SourcemaptError::NotFound(message)
+        } else {
+            SourcemaptError::Io(message)
+        });
+    }
+
+    Ok(output.stdout)
+}
+
+/// Calls `f` with the path and content of every text blob in the tree at `rev`, until it returns
+/// false. Blobs are streamed through a single `git cat-file --batch` process.
+fn walk_blobs<F>(path: &Path, rev: &str, mut f: F) -> Result<(), SourcemaptError>
+    where
+        F: FnMut(&str, &str) -> bool,
+{
+    let tree = git(path, &["ls-tree", "-r", "-z", "--full-tree", rev])?;
+
+    // Each entry is "<mode> <type> <oid>\t<path>"
+    let blobs = tree
+        .split(|b| *b == 0)
+        .filter_map(|entry| {
+            let entry = std::str::from_utf8(entry).ok()?;
+            let (meta, file) = entry.split_once('\t')?;
+            let mut meta = meta.split(' ');
+            let (_, kind, oid) = (meta.next()?, meta.next()?, meta.next()?);
+            if kind != "blob" {
+                return None;
+            }
+            Some((oid.to_owned(), file.to_owned()))
+        })
+        .collect::<Vec<(String, String)>>();
+
+    let mut child = Command::new("git")
+        .arg("-C")
+        .arg(path)
+        .args(["cat-file", "--batch"])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()?;
+
+    let mut stdin = child
+        .stdin
+        .take()
+        .ok_or_else(|| SourcemaptError::Io("failed to open git stdin".to_owned()))?;
+    let oids = blobs.iter().map(|(oid, _)| oid.clone()).collect::<Vec<String>>();
+    let writer = thread::spawn(move || {
+        for oid in oids {
+            if writeln!(stdin, "{}", oid).is_err() {
+                break;
+            }
+        }
+    });
+
+    let stdout = child
+        .stdout
+        .take()
+        .ok_or_else(|| SourcemaptError::Io("failed to open git stdout".to_owned()))?;
+    let mut stdout = BufReader::new(stdout);
+    let mut header = String::new();
+
+    for (_, file) in &blobs {
+        header.clear();
+        if stdout.read_line(&mut header)? == 0 {
+            break;
+        }
+
+        // "<oid> <type> <size>", or "<oid> missing"
+        let size = match header.trim().rsplit(' ').next().and_then(|v| v.parse::<usize>().ok()) {
+            Some(size) => size,
+            None => continue,
+        };
+
+        let mut blob = vec![0u8; size + 1]; // Trailing newline after each object
+        stdout.read_exact(&mut blob)?;
+        blob.truncate(size);
+
+        if size > MAX_BLOB_SIZE || blob.contains(&0) {
+            continue;
+        }
+
+        if !f(file, &String::from_utf8_lossy(&blob)) {
+            break;
+        }
+    }
+
+    drop(stdout);
+    let _ = child.kill();
+    let _ = child.wait();
+    let _ = writer.join();
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    const KIWI_GO: &str = "package pkg\n\ntype Kiwi struct{}\n\nfunc (k *Kiwi) Manage() {}\n";
+    const STORE_GO: &str = "package pkg\n\nfunc NewKiwi() *Kiwi { return &Kiwi{} }\n";
+
+    /// A repository with a single commit of `pkg/kiwi.go` and `pkg/store.go`, in the temp dir.
+    fn test_repo(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir()
+            .join(format!("sourcemapt-local-git-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(dir.join("pkg")).unwrap();
+        fs::write(dir.join("pkg/kiwi.go"), KIWI_GO).unwrap();
+        fs::write(dir.join("pkg/store.go"), STORE_GO).unwrap();
+
+        git(&dir, &["init", "-q"]).unwrap();
+        git(&dir, &["add", "."]).unwrap();
+        git(
+            &dir,
+            &[
+                "-c",
+                "user.name=test",
+                "-c",
+                "user.email=test@example.com",
+                "-c",
+                "commit.gpgsign=false",
+                "commit",
+                "-q",
+                "-m",
+                "kiwis",
+            ],
+        )
+            .unwrap();
+
+        dir
+    }
+
+    #[tokio::test]
+    async fn local_git_host_reads_files_at_a_revision() {
+        let dir = test_repo("read");
+        let host = LocalGitHost::new(dir.clone());
+
+        let oid = host.resolve_revision("", "HEAD").await;
+        let content = host.get_file_content("", "HEAD", "pkg/kiwi.go").await;
+        let listing = host.list_dir("", "HEAD", "", 2).await;
+        let _ = fs::remove_dir_all(&dir);
+
+        let oid = oid.unwrap();
+        assert_eq!(oid.len(), 40);
+        assert!(oid.chars().all(|c| c.is_ascii_hexdigit()));
+        assert_eq!(content.unwrap().content, KIWI_GO);
+        assert_eq!(
+            listing.unwrap().entries.iter().map(|v| (v.path.as_str(), v.kind)).collect::<Vec<_>>(),
+            vec![("pkg", "dir"), ("pkg/kiwi.go", "file"), ("pkg/store.go", "file")]
+        );
+    }
+
+    #[tokio::test]
+    async fn local_git_host_navigates_from_the_identifier_under_the_cursor() {
+        let dir = test_repo("navigate");
+        let host = LocalGitHost::new(dir.clone());
+
+        // The `Kiwi` of `*Kiwi` on the last line of pkg/store.go
+        let definition = host.get_definition("", "HEAD", "pkg/store.go", 2, 16).await;
+        let references = host.get_references("", "HEAD", "pkg/store.go", 2, 16, None).await;
+        let nothing = host.get_definition("", "HEAD", "pkg/store.go", 1, 0).await;
+        let _ = fs::remove_dir_all(&dir);
+
+        let definition = definition.unwrap();
+        assert_eq!(definition.definitions.len(), 1);
+        assert_eq!(definition.definitions[0].resource.path, "pkg/kiwi.go");
+        assert_eq!(definition.definitions[0].range.line_start, 2);
+        assert_eq!(definition.hover.unwrap().markdown, "```\ntype Kiwi struct{}\n```");
+
+        let references = references.unwrap();
+        assert_eq!(
+            references.references.iter().map(|v| (v.path.as_str(), v.line)).collect::<Vec<_>>(),
+            vec![("pkg/kiwi.go", 2), ("pkg/kiwi.go", 4), ("pkg/store.go", 2), ("pkg/store.go", 2)]
+        );
+        assert_eq!(references.next, None);
+
+        assert!(nothing.unwrap().definitions.is_empty());
+    }
+
+    #[tokio::test]
+    async fn local_git_host_reports_missing_revisions_and_files_as_not_found() {
+        let dir = test_repo("missing");
+        let host = LocalGitHost::new(dir.clone());
+
+        let revision = host.resolve_revision("", "no-such-branch").await;
+        let file = host.get_file_content("", "HEAD", "pkg/missing.go").await;
+        let _ = fs::remove_dir_all(&dir);
+
+        assert!(matches!(revision, Err(SourcemaptError::NotFound(_))));
+        assert!(matches!(file, Err(SourcemaptError::NotFound(_))));
+    }
+
+    #[tokio::test]
+    async fn local_git_host_reports_a_missing_repository_as_an_io_error() {
+        let dir = std::env::temp_dir()
+            .join(format!("sourcemapt-local-git-absent-{}", std::process::id()));
+        let host = LocalGitHost::new(dir);
+
+        let file = host.get_file_content("", "HEAD", "pkg/kiwi.go").await;
+
+        assert!(matches!(file, Err(SourcemaptError::Io(_))));
+    }
+}
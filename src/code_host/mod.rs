@@ -0,0 +1,117 @@
+pub mod local_git;
+pub mod sourcegraph;
+
+#[cfg(test)]
+pub mod fake;
+
+use crate::code_host::local_git::LocalGitHost;
+use crate::config::{Config, ConfigError};
+use crate::error::SourcemaptError;
+use crate::sourcegraph::client::SourcegraphClient;
+use crate::sourcegraph::definition_and_hover::GetDefinitionResult;
+use crate::sourcegraph::file_content::GetFileContentResult;
+use crate::sourcegraph::implementations::GetImplementationsResult;
+use crate::sourcegraph::list_dir::ListDirResult;
+use crate::sourcegraph::references::GetReferencesResult;
+use crate::sourcegraph::search_files::SearchFilesResult;
+use crate::sourcegraph::symbols::GetSymbolsResult;
+use async_trait::async_trait;
+use std::path::PathBuf;
+
+/// Somewhere code can be searched, read and navigated. `Sourcemapt` only talks to the code it is
+/// exploring through this trait.
+#[async_trait(?Send)]
+pub trait CodeHost {
+    /// The commit OID `rev` currently points to.
+    async fn resolve_revision(&self, repo: &str, rev: &str) -> Result<String, SourcemaptError>;
+
+    async fn search_files(
+        &self,
+        repo: &str,
+        rev: &str,
+        search_terms: &[String],
+    ) -> Result<SearchFilesResult, SourcemaptError>;
+
+    async fn get_file_content(
+        &self,
+        repo: &str,
+        rev: &str,
+        path: &str,
+    ) -> Result<GetFileContentResult, SourcemaptError>;
+
+    async fn get_definition(
+        &self,
+        repo: &str,
+        rev: &str,
+        path: &str,
+        line: u32,
+        char: u32,
+    ) -> Result<GetDefinitionResult, SourcemaptError>;
+
+    /// One page of the places that reference the symbol at `line` and `char`. `after` is the
+    /// `next` cursor of the previous page.
+    async fn get_references(
+        &self,
+        repo: &str,
+        rev: &str,
+        path: &str,
+        line: u32,
+        char: u32,
+        after: Option<&str>,
+    ) -> Result<GetReferencesResult, SourcemaptError>;
+
+    /// One page of the concrete implementations of the interface or method at `line` and `char`.
+    async fn get_implementations(
+        &self,
+        repo: &str,
+        rev: &str,
+        path: &str,
+        line: u32,
+        char: u32,
+        after: Option<&str>,
+    ) -> Result<GetImplementationsResult, SourcemaptError>;
+
+    /// Functions, types, methods and other named symbols whose name matches `query`, optionally
+    /// only those of one `kind`.
+    async fn get_symbols(
+        &self,
+        repo: &str,
+        rev: &str,
+        query: &str,
+        kind: Option<&str>,
+    ) -> Result<GetSymbolsResult, SourcemaptError>;
+
+    /// The files and directories under `path`, down to `depth` levels. Subdirectories are listed
+    /// one level at a time, so a large tree is never fetched past the first `MAX_DIR_ENTRIES`
+    /// entries.
+    async fn list_dir(
+        &self,
+        repo: &str,
+        rev: &str,
+        path: &str,
+        depth: usize,
+    ) -> Result<ListDirResult, SourcemaptError>;
+}
+
+/// Builds the provider selected by `code_host.provider`: `sourcegraph` (the default) or `local`.
+/// A `local` path given on the command line takes precedence over the config.
+pub fn from_config(config: &Config, local: Option<PathBuf>) -> Result<Box<dyn CodeHost>, ConfigError> {
+    if let Some(path) = local {
+        return Ok(Box::new(LocalGitHost::new(path)));
+    }
+
+    match config.code_host.provider.as_str() {
+        "sourcegraph" => {
+            Ok(Box::new(SourcegraphClient::new(&config.sourcegraph)?))
+        }
+        "local" => {
+            let path = config.code_host.path.clone().ok_or_else(|| {
+                ConfigError::Invalid(
+                    "Set 'code_host.path' in the configuration file to use the local provider".to_owned(),
+                )
+            })?;
+            Ok(Box::new(LocalGitHost::new(path)))
+        }
+        provider => Err(ConfigError::Invalid(format!("Unknown code host provider: {}", provider))),
+    }
+}
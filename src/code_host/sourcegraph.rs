@@ -0,0 +1,91 @@
+use crate::code_host::CodeHost;
+use crate::error::SourcemaptError;
+use crate::sourcegraph::client::SourcegraphClient;
+use crate::sourcegraph::definition_and_hover::GetDefinitionResult;
+use crate::sourcegraph::file_content::GetFileContentResult;
+use crate::sourcegraph::implementations::GetImplementationsResult;
+use crate::sourcegraph::list_dir::ListDirResult;
+use crate::sourcegraph::references::GetReferencesResult;
+use crate::sourcegraph::search_files::SearchFilesResult;
+use crate::sourcegraph::symbols::GetSymbolsResult;
+use async_trait::async_trait;
+
+#[async_trait(?Send)]
+impl CodeHost for SourcegraphClient {
+    async fn resolve_revision(&self, repo: &str, rev: &str) -> Result<String, SourcemaptError> {
+        Ok(SourcegraphClient::resolve_revision(self, repo, rev).await?)
+    }
+
+    async fn search_files(
+        &self,
+        repo: &str,
+        rev: &str,
+        search_terms: &[String],
+    ) -> Result<SearchFilesResult, SourcemaptError> {
+        SourcegraphClient::search_files(self, repo, rev, search_terms).await
+    }
+
+    async fn get_file_content(
+        &self,
+        repo: &str,
+        rev: &str,
+        path: &str,
+    ) -> Result<GetFileContentResult, SourcemaptError> {
+        SourcegraphClient::get_file_content(self, repo, rev, path).await
+    }
+
+    async fn get_definition(
+        &self,
+        repo: &str,
+        rev: &str,
+        path: &str,
+        line: u32,
+        char: u32,
+    ) -> Result<GetDefinitionResult, SourcemaptError> {
+        Ok(SourcegraphClient::get_definition(self, repo, rev, path, line, char).await?)
+    }
+
+    async fn get_references(
+        &self,
+        repo: &str,
+        rev: &str,
+        path: &str,
+        line: u32,
+        char: u32,
+        after: Option<&str>,
+    ) -> Result<GetReferencesResult, SourcemaptError> {
+        Ok(SourcegraphClient::get_references(self, repo, rev, path, line, char, after).await?)
+    }
+
+    async fn get_implementations(
+        &self,
+        repo: &str,
+        rev: &str,
+        path: &str,
+        line: u32,
+        char: u32,
+        after: Option<&str>,
+    ) -> Result<GetImplementationsResult, SourcemaptError> {
+        Ok(SourcegraphClient::get_implementations(self, repo, rev, path, line, char, after).await?)
+    }
+
+    async fn get_symbols(
+        &self,
+        repo: &str,
+        rev: &str,
+        query: &str,
+        kind: Option<&str>,
+    ) -> Result<GetSymbolsResult, SourcemaptError> {
+        Ok(SourcegraphClient::get_symbols(self, repo, rev, query, kind).await?)
+    }
+
+    async fn list_dir(
+        &self,
+        repo: &str,
+        rev: &str,
+        path: &str,
+        depth: usize,
+    ) -> Result<ListDirResult, SourcemaptError> {
+        Ok(SourcegraphClient::list_dir(self, repo, rev, path, depth).await?)
+    }
+}
@@ -0,0 +1,378 @@
+use crate::llm::Protocol;
+use crate::sourcegraph::client::SourcegraphConfig;
+use serde::Deserialize;
+use std::error::Error;
+use std::fmt::{Display, Formatter, Result as FmtResult};
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+use toml::Value;
+
+const TEMPLATE: &str = r#"[llm]
+backend = "openai"
+key = ""
+
+[defaults]
+repo = "github.com/kubernetes/kubernetes"
+rev = "master"
+"#;
+
+#[derive(Debug)]
+pub enum ConfigError {
+    NoConfigDir,
+    Io(PathBuf, io::Error),
+    Parse(PathBuf, toml::de::Error),
+    /// No configuration existed, so a template was written to this path.
+    Created(PathBuf),
+    UnknownProfile(String),
+    Invalid(String),
+}
+
+impl Display for ConfigError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        match self {
+            ConfigError::NoConfigDir => write!(f, "Unable to find config directory"),
+            ConfigError::Io(path, e) => write!(f, "{}: {}", path.display(), e),
+            ConfigError::Parse(path, e) => write!(f, "{}: {}", path.display(), e),
+            ConfigError::Created(path) => write!(
+                f,
+                "Created a new configuration file at: {}\nSet the 'llm.key' value in the file before using the program.",
+                path.display()
+            ),
+            ConfigError::UnknownProfile(name) => write!(f, "Unknown profile: {}", name),
+            ConfigError::Invalid(message) => write!(f, "{}", message),
+        }
+    }
+}
+
+impl Error for ConfigError {}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct Config {
+    /// Top-level API key from before the `[llm]` section existed.
+    pub key: Option<String>,
+    pub llm: LlmConfig,
+    pub code_host: CodeHostConfig,
+    pub sourcegraph: SourcegraphConfig,
+    pub defaults: DefaultsConfig,
+    pub budget: BudgetConfig,
+    pub filters: FilterConfig,
+    pub output: OutputConfig,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+pub struct LlmConfig {
+    /// `openai`, `compatible` or `scripted`.
+    pub backend: String,
+    /// `text` (the default) or `tools`. Backends without tools support fall back to `text`.
+    pub protocol: Protocol,
+    pub key: Option<String>,
+    /// Base URL of an OpenAI-compatible server, for the `compatible` backend.
+    pub url: Option<String>,
+    pub model: Option<String>,
+    pub temperature: Option<f32>,
+    pub top_p: Option<f32>,
+    pub max_tokens: Option<u32>,
+    /// Completions returned in order by the `scripted` backend.
+    pub completions: Vec<String>,
+}
+
+impl Default for LlmConfig {
+    fn default() -> Self {
+        Self {
+            backend: "openai".to_owned(),
+            protocol: Protocol::default(),
+            key: None,
+            url: None,
+            model: None,
+            temperature: None,
+            top_p: None,
+            max_tokens: None,
+            completions: Vec::new(),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+pub struct CodeHostConfig {
+    /// `sourcegraph` or `local`.
+    pub provider: String,
+    /// Checkout to explore with the `local` provider.
+    pub path: Option<PathBuf>,
+}
+
+impl Default for CodeHostConfig {
+    fn default() -> Self {
+        Self {
+            provider: "sourcegraph".to_owned(),
+            path: None,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+pub struct DefaultsConfig {
+    pub repo: String,
+    pub rev: String,
+}
+
+impl Default for DefaultsConfig {
+    fn default() -> Self {
+        Self {
+            repo: "github.com/kubernetes/kubernetes".to_owned(),
+            rev: "master".to_owned(),
+        }
+    }
+}
+
+impl DefaultsConfig {
+    /// The repository and revision given on the command line, or the configured ones for
+    /// whichever wasn't given.
+    pub fn resolve(&self, repo: Option<String>, rev: Option<String>) -> (String, String) {
+        (
+            repo.unwrap_or_else(|| self.repo.clone()),
+            rev.unwrap_or_else(|| self.rev.clone()),
+        )
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct BudgetConfig {
+    /// Model calls allowed per question before the session gives up.
+    pub max_turns: usize,
+    /// Estimated prompt size above which old command output is hidden. Leave room for the
+    /// completion within the model's context window.
+    pub max_prompt_tokens: usize,
+    /// Failed commands in a row after which the session gives up. Errors are shown to the model
+    /// so it can correct itself until then.
+    pub max_consecutive_errors: usize,
+    /// Commands from one response that run at the same time. 1 runs them one after another.
+    pub max_concurrent_commands: usize,
+}
+
+impl Default for BudgetConfig {
+    fn default() -> Self {
+        Self {
+            max_turns: 20,
+            max_prompt_tokens: 6000,
+            max_consecutive_errors: 5,
+            max_concurrent_commands: 4,
+        }
+    }
+}
+
+/// Which kinds of boilerplate are elided from code shown to the model.
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+pub struct FilterConfig {
+    /// Comment blocks mentioning a copyright or licence.
+    pub licence: bool,
+    /// "Code generated ... DO NOT EDIT" notices.
+    pub generated: bool,
+    /// Runs of at least `min_import_lines` import, use or include lines.
+    pub imports: bool,
+    pub min_import_lines: usize,
+    /// Comment blocks longer than `max_comment_lines`, except for their first line.
+    pub comments: bool,
+    pub max_comment_lines: usize,
+}
+
+impl Default for FilterConfig {
+    fn default() -> Self {
+        Self {
+            licence: true,
+            generated: true,
+            imports: true,
+            min_import_lines: 4,
+            comments: true,
+            max_comment_lines: 8,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+pub struct OutputConfig {
+    /// Print the whole message history once a non-interactive session ends.
+    pub print_history: bool,
+    /// Include hidden messages when printing the history.
+    pub show_hidden: bool,
+}
+
+impl Default for OutputConfig {
+    fn default() -> Self {
+        Self {
+            print_history: true,
+            show_hidden: true,
+        }
+    }
+}
+
+impl Config {
+    fn path() -> Result<PathBuf, ConfigError> {
+        let config_dir = dirs::config_dir().ok_or(ConfigError::NoConfigDir)?;
+        Ok(config_dir.join("sourcemapt.toml"))
+    }
+
+    /// Reads the configuration file, creating a template if there isn't one, and applies the
+    /// `[profile.<name>]` section on top of the base configuration if a profile is given.
+    pub fn load(profile: Option<&str>) -> Result<Config, ConfigError> {
+        let config_path = Self::path()?;
+
+        if !config_path.exists() {
+            if let Some(config_dir) = config_path.parent() {
+                fs::create_dir_all(config_dir)
+                    .map_err(|e| ConfigError::Io(config_dir.to_owned(), e))?;
+            }
+            fs::write(&config_path, TEMPLATE).map_err(|e| ConfigError::Io(config_path.clone(), e))?;
+            return Err(ConfigError::Created(config_path));
+        }
+
+        let content = fs::read_to_string(&config_path)
+            .map_err(|e| ConfigError::Io(config_path.clone(), e))?;
+
+        Self::parse(&content, profile).map_err(|e| match e {
+            ConfigError::Parse(_, e) => ConfigError::Parse(config_path, e),
+            e => e,
+        })
+    }
+
+    pub fn parse(content: &str, profile: Option<&str>) -> Result<Config, ConfigError> {
+        let mut value = content
+            .parse::<Value>()
+            .map_err(|e| ConfigError::Parse(PathBuf::new(), e))?;
+
+        let profiles = match &mut value {
+            Value::Table(table) => table.remove("profile"),
+            _ => None,
+        };
+
+        if let Some(name) = profile {
+            let overrides = profiles
+                .as_ref()
+                .and_then(|v| v.get(name))
+                .ok_or_else(|| ConfigError::UnknownProfile(name.to_owned()))?;
+            merge(&mut value, overrides.clone());
+        }
+
+        value
+            .try_into::<Config>()
+            .map_err(|e| ConfigError::Parse(PathBuf::new(), e))
+    }
+
+    pub fn llm_key(&self) -> Option<&str> {
+        self.llm
+            .key
+            .as_deref()
+            .filter(|v| !v.is_empty())
+            .or(self.key.as_deref().filter(|v| !v.is_empty()))
+    }
+}
+
+/// Recursively overlays `overrides` onto `base`: tables are merged key by key, anything else
+/// is replaced.
+fn merge(base: &mut Value, overrides: Value) {
+    match (base, overrides) {
+        (Value::Table(base), Value::Table(overrides)) => {
+            for (key, value) in overrides {
+                match base.get_mut(&key) {
+                    Some(existing) => merge(existing, value),
+                    None => {
+                        base.insert(key, value);
+                    }
+                }
+            }
+        }
+        (base, overrides) => *base = overrides,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cli::{self, CliCommand};
+
+    const PROFILE_CONFIG: &str = r#"
+    key = "legacy-key"
+
+    [llm]
+    model = "gpt-4"
+
+    [defaults]
+    repo = "github.com/kubernetes/kubernetes"
+    rev = "master"
+
+    [profile.local]
+    llm = { backend = "compatible", url = "http://localhost:8080/v1", key = "local-key" }
+    defaults = { rev = "release-1.27" }
+    "#;
+
+    #[test]
+    fn config_without_profile_uses_base_sections() {
+        let config = Config::parse(PROFILE_CONFIG, None).unwrap();
+
+        assert_eq!(config.llm.backend, "openai");
+        assert_eq!(config.llm.model.as_deref(), Some("gpt-4"));
+        assert_eq!(config.defaults.rev, "master");
+        // Missing sections keep their defaults
+        assert_eq!(config.budget.max_turns, BudgetConfig::default().max_turns);
+    }
+
+    #[test]
+    fn config_profile_overrides_only_the_keys_it_sets() {
+        let config = Config::parse(PROFILE_CONFIG, Some("local")).unwrap();
+
+        assert_eq!(config.llm.backend, "compatible");
+        assert_eq!(config.llm.url.as_deref(), Some("http://localhost:8080/v1"));
+        assert_eq!(config.llm.model.as_deref(), Some("gpt-4"));
+        assert_eq!(config.defaults.repo, "github.com/kubernetes/kubernetes");
+        assert_eq!(config.defaults.rev, "release-1.27");
+        assert_eq!(config.llm_key(), Some("local-key"));
+    }
+
+    #[test]
+    fn config_rejects_unknown_profile() {
+        assert!(matches!(
+            Config::parse(PROFILE_CONFIG, Some("missing")),
+            Err(ConfigError::UnknownProfile(name)) if name == "missing"
+        ));
+    }
+
+    #[test]
+    fn config_falls_back_to_legacy_top_level_key() {
+        let config = Config::parse(PROFILE_CONFIG, None).unwrap();
+        assert_eq!(config.llm_key(), Some("legacy-key"));
+
+        let config = Config::parse("key = \"legacy-key\"\n[llm]\nkey = \"\"\n", None).unwrap();
+        assert_eq!(config.llm_key(), Some("legacy-key"));
+
+        let config = Config::parse("", None).unwrap();
+        assert_eq!(config.llm_key(), None);
+    }
+
+    #[test]
+    fn cli_arguments_take_precedence_over_config() {
+        let cli = cli::from_matches(&cli::build().get_matches_from([
+            "sourcemapt",
+            "ask",
+            "--profile",
+            "local",
+            "--repo",
+            "github.com/example/repo",
+            "What does StatesHasBeenSynced do?",
+        ]));
+        let config = Config::parse(PROFILE_CONFIG, cli.profile.as_deref()).unwrap();
+
+        let (repo, rev) = match cli.command {
+            CliCommand::Ask { repo, rev, .. } => config.defaults.resolve(repo, rev),
+            _ => panic!("expected an ask command"),
+        };
+        assert_eq!(repo, "github.com/example/repo");
+        assert_eq!(rev, "release-1.27");
+    }
+}
@@ -22,6 +22,42 @@ for example `!SEARCH_FILES "kiwi manager" "KiwisHaveBeenManaged`, and you will g
 ```
 Multiple arguments will perform and OR search. Try using lots of different keyword combinations to ensure you get the best results. If you don't find results that are helpful, or if you think there may be better keywords to try, repeat the SEARCH_FILES command.
 
+If you want to see what is in a directory of the repository, you can output the following:
+```
+!LIST_DIR "<path>" "<depth>"
+```
+The depth is optional and defaults to 1; a depth of 2 also lists the contents of each subdirectory, and so on. For example `!LIST_DIR "pkg/kiwilet" "1"`, and you will get results like:
+```
+{
+  "entries": [
+    { "path": "pkg/kiwilet/kiwimanager", "type": "dir" },
+    { "path": "pkg/kiwilet/kiwilet.go", "type": "file", "size": 104213 }
+  ]
+}
+```
+Use `!LIST_DIR ""` to see the root of the repository.
+
+If you know the name of a function, type or method, you can find where it is defined with the following:
+```
+!SYMBOLS "<query>" "<kind>"
+```
+The kind (function, method, struct, interface, class, ...) is optional. For example `!SYMBOLS "^KiwisHaveBeenManaged$" "method"`, and you will get results like:
+```
+{
+  "symbols": [
+    {
+      "name": "KiwisHaveBeenManaged",
+      "kind": "method",
+      "container": "kiwiManager",
+      "path": "pkg/generated/kiwi/core/v1/zz_generated_kiwis_manager.go",
+      "line": 737,
+      "character": 24
+    }
+  ]
+}
+```
+The line and character can be passed to !JUMP, !HOVER and !REFERENCES.
+
 Once you find relevant files, if you want to see a snippet of code at a line number and n lines after it, you can output the following:
 ```
 !READ_LINES "<path> "<line>" "<n>"
@@ -36,6 +72,7 @@ for example `!READ_LINES "pkg/generated/kiwi/core/v1/zz_generated_kiwis_manager.
 743 | ...
 ```
 If you want to see more lines, repeat the !READ_LINES command with a new line number and n.
+Boilerplate such as licence headers, import blocks and long comments is replaced by a marker like `[lines 1-14 elided: licence header]`.
 Don't show the user the full output of this source code; they can already see it. Only show them the relevant lines of code and explain why they are relevant.
 
 If you want to jump to the definition of a symbol (function call, class name, etc.), you can output the following:
@@ -44,16 +81,77 @@ If you want to jump to the definition of a symbol (function call, class name, et
 ```
 for example `!JUMP "pkg/generated/kiwi/core/v1/zz_generated_kiwis_manager.go" "739" "27" "10"`, and you will get results like:
 ```
+func (km *kiwiManager) KiwisHaveBeenManaged() bool
+KiwisHaveBeenManaged reports whether the kiwis have been managed.
+
 22 | func (km *kiwiManager) KiwisHaveBeenManaged() bool {
 23 |     o, ok := k.lifecycle.(lifecycle.ObjectLifecycleCondition)
 24 |     return !ok || o.HasCreate()
 25 | }
 26 | ...
 ```
+The signature and documentation of the symbol come first, when they are available.
+If the symbol has several definitions, such as overloads, you get a numbered list of all of them with their repository, path and line, followed by the first lines of each. A definition in a different repository is marked `(in another repository)`; !READ_LINES only reads the repository you are exploring.
+
+If you only want the signature and documentation of a symbol, without reading its definition, you can output the following:
+```
+!HOVER "<path>" "<line>" "<character>"
+```
+for example `!HOVER "pkg/generated/kiwi/core/v1/zz_generated_kiwis_manager.go" "739" "27"`.
+
+If you want to find the places that use a symbol, you can output the following:
+```
+!REFERENCES "<path>" "<line>" "<character>"
+```
+for example `!REFERENCES "pkg/generated/kiwi/core/v1/zz_generated_kiwis_manager.go" "738" "24"`, and you will get results like:
+```
+{
+  "references": [
+    {
+      "repo": "github.com/kubernetes/kubernetes",
+      "path": "pkg/kiwilet/kiwilet.go",
+      "line": 1516,
+      "character": 42,
+      "preview": "nodestatus.KiwisInUse(kl.kiwiManager.KiwisHaveBeenManaged,"
+    }
+  ],
+  "next": "MjA="
+}
+```
+If there is a `next` value, more references exist; repeat the command with it as a fourth argument to see them, for example `!REFERENCES "pkg/generated/kiwi/core/v1/zz_generated_kiwis_manager.go" "738" "24" "MjA="`.
+
+If you want to find the concrete implementations of an interface or method, you can output the following:
+```
+!IMPLEMENTATIONS "<path>" "<line>" "<character>"
+```
+for example `!IMPLEMENTATIONS "pkg/kiwilet/kiwimanager/kiwi_manager.go" "42" "5"`. The results look like those of !REFERENCES, with an `implementations` list instead of `references`, and are paged the same way.
+
+When you are about to explore, for example browsing directories or search results to find the file you need, you can start a scope with the following:
+```
+!SCOPE "<name>"
+```
+for example `!SCOPE "find the kiwi manager"`. Once you have found what you were looking for, close the scope with the following:
+```
+!DECIDE "<rationale>"
+```
+for example `!DECIDE "KiwisHaveBeenManaged is defined in pkg/generated/kiwi/core/v1/zz_generated_kiwis_manager.go at line 738"`. The output of the commands in the scope is then removed from the conversation and replaced by your rationale, so include everything you still need in it, such as file paths and line numbers. A new scope starts automatically after each decision.
+
+If a command fails, you will get its error instead of its results, like `{"error":"line 900 is past the end of pkg/kiwilet/kiwilet.go, which has 850 lines","command":"!READ_LINES \"pkg/kiwilet/kiwilet.go\" \"900\" \"10\""}`. Fix the command and try again.
 
 Remember, if you think you can find more code, repeat the !SEARCH_FILES command with new keywords. Don't make assumptions about how functions are implemented without seeing their source code.
 "#;
 
+pub(crate) const SYSTEM_TOOLS: &str = r#"
+You are a programming assistant capable of searching for source code to answer user questions. You have tools to search the repository for files, list directories, find symbols by name, read lines from a file, and jump to the definition of a symbol, show the signature and documentation of a symbol, find the references to a symbol, and find the implementations of an interface or method.
+
+Try using lots of different keyword combinations when searching to ensure you get the best results. If you don't find results that are helpful, or if you think there may be better keywords to try, search again.
+Don't show the user the full output of source code you read; they can already see it. Only show them the relevant lines of code and explain why they are relevant.
+
+When you explore, for example browsing directories to find the file you need, start a scope first and decide once you have found it; the tool output from the scope is then replaced by your rationale to save space.
+
+Remember, if you think you can find more code, search again with new keywords. Don't make assumptions about how functions are implemented without seeing their source code.
+"#;
+
 pub(crate) const TEST1: &str = r#"
 !SEARCH_FILES "kubelet volume manager reconciler synced"
 
@@ -87,5 +185,5 @@ Do you have anything more to say? If so, say `CONTINUE`. Otherwise, say `COMPLET
 pub(crate) const ASK_TO_SUMMARIZE: &str = r#"
 Are you able to confidently answer my initial question in detail now?
 If so, say `IN SUMMARY:`, followed by your answer, with any relevant source code snippets.
-Otherwise, continue to use SEARCH_FILES, READ_LINES, and JUMP.
+Otherwise, continue to use SEARCH_FILES, LIST_DIR, SYMBOLS, READ_LINES, JUMP, HOVER, REFERENCES, and IMPLEMENTATIONS.
 "#;
@@ -0,0 +1,95 @@
+use reqwest::StatusCode;
+use std::error::Error;
+use std::fmt::{Display, Formatter, Result as FmtResult};
+use std::io;
+
+/// Everything that can go wrong while answering a question. Auth and rate limit errors need the
+/// user's attention, while the model can usually recover from not-found and invalid commands.
+#[derive(Debug)]
+pub enum SourcemaptError {
+    /// The code host or model API rejected the credentials.
+    Auth(String),
+    /// A repository, revision, file or symbol doesn't exist.
+    NotFound(String),
+    RateLimited(String),
+    /// The request couldn't be sent, or the server failed to answer it.
+    Network(String),
+    /// The model API returned an error or a completion that can't be used.
+    Llm(String),
+    /// A response, command or tool call couldn't be understood.
+    Parse(String),
+    /// A command's arguments don't fit what they refer to, e.g. a line past the end of a file.
+    InvalidCommand(String),
+    /// Local files or git couldn't be read or written.
+    Io(String),
+    /// A limit from the `[budget]` configuration was reached, such as the number of model calls.
+    Budget(String),
+}
+
+impl SourcemaptError {
+    pub fn from_status(status: StatusCode, message: String) -> Self {
+        match status {
+            StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN => SourcemaptError::Auth(message),
+            StatusCode::NOT_FOUND => SourcemaptError::NotFound(message),
+            StatusCode::TOO_MANY_REQUESTS => SourcemaptError::RateLimited(message),
+            _ => SourcemaptError::Network(message),
+        }
+    }
+
+    /// Whether retrying with a different command can't help, so the session should end.
+    pub fn is_fatal(&self) -> bool {
+        matches!(self, SourcemaptError::Auth(_) | SourcemaptError::RateLimited(_))
+    }
+}
+
+impl Display for SourcemaptError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        match self {
+            SourcemaptError::Auth(message) => write!(f, "authentication failed: {}", message),
+            SourcemaptError::NotFound(message) => write!(f, "not found: {}", message),
+            SourcemaptError::RateLimited(message) => write!(f, "rate limited: {}", message),
+            SourcemaptError::Network(message) => write!(f, "network error: {}", message),
+            SourcemaptError::Llm(message) => write!(f, "model error: {}", message),
+            SourcemaptError::Parse(message) => write!(f, "failed to parse: {}", message),
+            SourcemaptError::InvalidCommand(message) => write!(f, "invalid command: {}", message),
+            SourcemaptError::Io(message) => write!(f, "{}", message),
+            SourcemaptError::Budget(message) => write!(f, "budget exhausted: {}", message),
+        }
+    }
+}
+
+impl Error for SourcemaptError {}
+
+impl From<reqwest::Error> for SourcemaptError {
+    fn from(e: reqwest::Error) -> Self {
+        match e.status() {
+            Some(status) => SourcemaptError::from_status(status, e.to_string()),
+            None if e.is_decode() => SourcemaptError::Parse(e.to_string()),
+            None => SourcemaptError::Network(e.to_string()),
+        }
+    }
+}
+
+impl From<serde_json::Error> for SourcemaptError {
+    fn from(e: serde_json::Error) -> Self {
+        SourcemaptError::Parse(e.to_string())
+    }
+}
+
+impl From<regex::Error> for SourcemaptError {
+    fn from(e: regex::Error) -> Self {
+        SourcemaptError::Parse(e.to_string())
+    }
+}
+
+impl From<io::Error> for SourcemaptError {
+    fn from(e: io::Error) -> Self {
+        SourcemaptError::Io(e.to_string())
+    }
+}
+
+impl From<tokio::task::JoinError> for SourcemaptError {
+    fn from(e: tokio::task::JoinError) -> Self {
+        SourcemaptError::Io(e.to_string())
+    }
+}
@@ -0,0 +1,350 @@
+use crate::config::FilterConfig;
+use crate::types::{CodeBlock, Elision};
+
+/// Finds lines of a code block that aren't worth the tokens, such as licence headers.
+pub trait Filter {
+    /// Ranges of `lines`, read from the file at `path`, to elide, as offsets from the first line.
+    fn find(&self, path: &str, lines: &[String]) -> Vec<Elision>;
+}
+
+/// The filters applied to every `CodeBlock` before it's shown to the model.
+pub struct Pipeline {
+    filters: Vec<Box<dyn Filter>>,
+}
+
+impl Pipeline {
+    pub fn new(filters: Vec<Box<dyn Filter>>) -> Self {
+        Self { filters }
+    }
+
+    /// Records what every filter found in `code.elisions`. The lines themselves are kept, so
+    /// line numbers don't shift.
+    pub fn apply(&self, code: &mut CodeBlock) {
+        let mut elisions = self.filters
+            .iter()
+            .flat_map(|v| v.find(&code.path, &code.lines))
+            .collect::<Vec<Elision>>();
+        elisions.sort_by_key(|v| v.offset);
+
+        // Overlapping ranges are merged, keeping the reason of the first
+        let mut merged: Vec<Elision> = Vec::new();
+        for elision in elisions {
+            if let Some(last) = merged.last_mut() {
+                if elision.offset <= last.offset + last.len {
+                    last.len = last.len.max(elision.offset + elision.len - last.offset);
+                    continue;
+                }
+            }
+            merged.push(elision);
+        }
+
+        code.elisions = merged;
+    }
+}
+
+impl Default for Pipeline {
+    fn default() -> Self {
+        from_config(&FilterConfig::default())
+    }
+}
+
+pub fn from_config(config: &FilterConfig) -> Pipeline {
+    let mut filters: Vec<Box<dyn Filter>> = Vec::new();
+
+    if config.licence {
+        filters.push(Box::new(LicenceFilter));
+    }
+    if config.generated {
+        filters.push(Box::new(GeneratedFilter));
+    }
+    if config.imports {
+        filters.push(Box::new(ImportFilter {
+            min_lines: config.min_import_lines,
+        }));
+    }
+    if config.comments {
+        filters.push(Box::new(CommentFilter {
+            max_lines: config.max_comment_lines,
+        }));
+    }
+
+    Pipeline::new(filters)
+}
+
+/// Comment runs mentioning a copyright or licence.
+pub struct LicenceFilter;
+
+impl Filter for LicenceFilter {
+    fn find(&self, path: &str, lines: &[String]) -> Vec<Elision> {
+        comment_runs(path, lines)
+            .into_iter()
+            .filter(|(start, end)| end - start >= 3)
+            .filter(|(start, end)| {
+                let text = lines[*start..*end].join("\n").to_lowercase();
+                text.contains("copyright") || text.contains("license") || text.contains("licence")
+            })
+            .map(|(start, end)| Elision::new(start, end - start, "licence header"))
+            .collect()
+    }
+}
+
+/// Comment runs carrying a "generated code, do not edit" marker.
+pub struct GeneratedFilter;
+
+impl Filter for GeneratedFilter {
+    fn find(&self, path: &str, lines: &[String]) -> Vec<Elision> {
+        comment_runs(path, lines)
+            .into_iter()
+            .filter(|(start, end)| {
+                let text = lines[*start..*end].join("\n").to_lowercase();
+                text.contains("code generated") || text.contains("do not edit") || text.contains("@generated")
+            })
+            .map(|(start, end)| Elision::new(start, end - start, "generated-code notice"))
+            .collect()
+    }
+}
+
+/// Blocks of at least `min_lines` import, use or include lines, blank lines included.
+pub struct ImportFilter {
+    pub min_lines: usize,
+}
+
+impl Filter for ImportFilter {
+    fn find(&self, _path: &str, lines: &[String]) -> Vec<Elision> {
+        let mut elisions = Vec::new();
+        let mut i = 0;
+
+        while i < lines.len() {
+            if !is_import(lines[i].trim()) {
+                i += 1;
+                continue;
+            }
+
+            let start = i;
+            let mut end = i;
+
+            while i < lines.len() {
+                let line = lines[i].trim();
+                if line.is_empty() {
+                    i += 1;
+                    continue;
+                }
+                if !is_import(line) {
+                    break;
+                }
+
+                // `import (`, `use a::{` and the like go on until their brackets are closed
+                let mut depth = bracket_depth(line);
+                i += 1;
+                while depth > 0 && i < lines.len() {
+                    depth += bracket_depth(&lines[i]);
+                    i += 1;
+                }
+                end = i;
+            }
+
+            if end - start >= self.min_lines {
+                elisions.push(Elision::new(start, end - start, "imports"));
+            }
+            i = end;
+        }
+
+        elisions
+    }
+}
+
+/// Comment runs longer than `max_lines`. The first line is kept, since it usually summarises
+/// the rest.
+pub struct CommentFilter {
+    pub max_lines: usize,
+}
+
+impl Filter for CommentFilter {
+    fn find(&self, path: &str, lines: &[String]) -> Vec<Elision> {
+        comment_runs(path, lines)
+            .into_iter()
+            .filter(|(start, end)| end - start > self.max_lines)
+            .map(|(start, end)| Elision::new(start + 1, end - start - 1, "comment"))
+            .collect()
+    }
+}
+
+fn is_import(line: &str) -> bool {
+    ["import ", "import(", "use ", "pub use ", "from ", "#include", "using ", "require ", "extern crate "]
+        .iter()
+        .any(|v| line.starts_with(v))
+}
+
+fn bracket_depth(line: &str) -> i32 {
+    line.chars()
+        .map(|c| match c {
+            '(' | '{' => 1,
+            ')' | '}' => -1,
+            _ => 0,
+        })
+        .sum()
+}
+
+/// Whether `#` starts a comment in the language of the file at `path`. Elsewhere, such as in
+/// Markdown, a `# ` line is content.
+fn has_hash_comments(path: &str) -> bool {
+    let name = path.rsplit('/').next().unwrap_or(path);
+    if ["Makefile", "Dockerfile", "BUILD", "WORKSPACE"].contains(&name) {
+        return true;
+    }
+
+    let extension = match name.rsplit_once('.') {
+        Some((_, extension)) => extension.to_lowercase(),
+        None => return false,
+    };
+    [
+        "py", "sh", "bash", "zsh", "rb", "pl", "pm", "r", "yaml", "yml", "toml", "cfg", "conf",
+        "ini", "mk", "cmake", "tf", "nix", "ex", "exs", "jl", "ps1", "bzl", "star",
+    ]
+        .contains(&extension.as_str())
+}
+
+/// Start and end (exclusive) of each run of consecutive comment lines in the file at `path`.
+fn comment_runs(path: &str, lines: &[String]) -> Vec<(usize, usize)> {
+    let hash_comments = has_hash_comments(path);
+    let mut runs = Vec::new();
+    let mut run_start = None;
+    let mut in_block = false;
+
+    for (i, line) in lines.iter().enumerate() {
+        let line = line.trim();
+
+        let is_comment = if in_block {
+            in_block = !line.contains("*/");
+            true
+        } else if let Some(rest) = line.strip_prefix("/*") {
+            in_block = !rest.contains("*/");
+            true
+        } else {
+            // `*` lines also catch the middle of a block comment that started before the block
+            line.starts_with("//")
+                || (hash_comments && (line == "#" || line.starts_with("# ")))
+                || line == "*"
+                || line.starts_with("* ")
+                || line.starts_with("*/")
+        };
+
+        match (is_comment, run_start) {
+            (true, None) => run_start = Some(i),
+            (false, Some(start)) => {
+                runs.push((start, i));
+                run_start = None;
+            }
+            _ => {}
+        }
+    }
+
+    if let Some(start) = run_start {
+        runs.push((start, lines.len()));
+    }
+
+    runs
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::tests::code_block;
+
+    /// `(offset, len, reason)` of each range the default pipeline elides from `lines`, read from a
+    /// Go file.
+    fn elided(lines: &[&str]) -> Vec<(usize, usize, String)> {
+        elided_in("pkg/reconciler.go", lines)
+    }
+
+    fn elided_in(path: &str, lines: &[&str]) -> Vec<(usize, usize, String)> {
+        let mut code = code_block(lines);
+        code.path = path.to_owned();
+        Pipeline::default().apply(&mut code);
+        code.elisions
+            .into_iter()
+            .map(|v| (v.offset, v.len, v.reason))
+            .collect()
+    }
+
+    const ORDINARY_CODE: &[&str] = &[
+        "package reconciler",
+        "",
+        "import \"fmt\"",
+        "",
+        "// StatesHasBeenSynced reports whether the actual state has been populated.",
+        "func (rc *reconciler) StatesHasBeenSynced() bool {",
+        "\treturn rc.synced // not a licence",
+        "}",
+    ];
+
+    #[test]
+    fn filters_leave_ordinary_code_alone() {
+        assert_eq!(elided(ORDINARY_CODE), Vec::new());
+    }
+
+    #[test]
+    fn licence_filter_elides_licence_header() {
+        let mut lines = vec![
+            "/*",
+            "Copyright 2019 The Kubernetes Authors.",
+            "Licensed under the Apache License, Version 2.0.",
+            "*/",
+            "",
+        ];
+        lines.extend(ORDINARY_CODE);
+
+        assert_eq!(elided(&lines), vec![(0, 4, "licence header".to_owned())]);
+    }
+
+    #[test]
+    fn generated_filter_elides_generated_code_notice() {
+        let mut lines = vec!["// Code generated by protoc-gen-go. DO NOT EDIT.", ""];
+        lines.extend(ORDINARY_CODE);
+
+        assert_eq!(elided(&lines), vec![(0, 1, "generated-code notice".to_owned())]);
+    }
+
+    #[test]
+    fn import_filter_elides_long_import_blocks() {
+        let lines = [
+            "package reconciler",
+            "",
+            "import (",
+            "\t\"fmt\"",
+            "\t\"time\"",
+            ")",
+            "",
+            "func run() {}",
+        ];
+
+        assert_eq!(elided(&lines), vec![(2, 4, "imports".to_owned())]);
+    }
+
+    #[test]
+    fn comment_filter_keeps_first_line_of_long_comments() {
+        let mut lines = vec!["// StatesHasBeenSynced reports whether the actual state has been populated."];
+        lines.extend(["// More detail."; 9]);
+        lines.push("func (rc *reconciler) StatesHasBeenSynced() bool { return rc.synced }");
+
+        assert_eq!(elided(&lines), vec![(1, 9, "comment".to_owned())]);
+    }
+
+    const HASH_LICENCE: &[&str] = &[
+        "# Copyright 2019 The Kubernetes Authors.",
+        "# Licensed under the Apache License, Version 2.0.",
+        "# See the LICENSE file for details.",
+        "",
+        "print(\"synced\")",
+    ];
+
+    #[test]
+    fn licence_filter_elides_hash_comments_in_hash_comment_languages() {
+        assert_eq!(elided_in("hack/sync.py", HASH_LICENCE), vec![(0, 3, "licence header".to_owned())]);
+    }
+
+    #[test]
+    fn filters_keep_markdown_headings() {
+        assert_eq!(elided_in("docs/LICENSE.md", HASH_LICENCE), Vec::new());
+    }
+}
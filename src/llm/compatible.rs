@@ -0,0 +1,309 @@
+use crate::error::SourcemaptError;
+use crate::llm::{ChatBackend, ChatMessage, ChatRole, Completion, ModelParameters, ToolCall, ToolDefinition};
+use async_trait::async_trait;
+use reqwest::Client;
+use serde::{Deserialize, Serialize};
+
+/// Talks to any server exposing an OpenAI-style `/chat/completions` endpoint, such as the
+/// llama.cpp server or vLLM.
+pub struct CompatibleBackend {
+    client: Client,
+    url: String,
+    key: Option<String>,
+    parameters: ModelParameters,
+}
+
+#[derive(Serialize)]
+struct CompletionRequest<'a> {
+    model: &'a str,
+    messages: Vec<RequestMessage<'a>>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    tools: Vec<RequestTool<'a>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    temperature: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    top_p: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    max_tokens: Option<u32>,
+    /// Asks for server-sent events carrying deltas instead of a single response.
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    stream: bool,
+}
+
+#[derive(Serialize)]
+struct RequestMessage<'a> {
+    role: &'static str,
+    content: &'a str,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    tool_calls: Vec<WireToolCall>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tool_call_id: Option<&'a str>,
+}
+
+#[derive(Serialize)]
+struct RequestTool<'a> {
+    #[serde(rename = "type")]
+    kind: &'static str,
+    function: RequestFunction<'a>,
+}
+
+#[derive(Serialize)]
+struct RequestFunction<'a> {
+    name: &'a str,
+    description: &'a str,
+    parameters: &'a serde_json::Value,
+}
+
+#[derive(Serialize, Deserialize)]
+struct WireToolCall {
+    id: String,
+    #[serde(rename = "type", default = "function_type")]
+    kind: String,
+    function: WireFunctionCall,
+}
+
+#[derive(Serialize, Deserialize)]
+struct WireFunctionCall {
+    name: String,
+    arguments: String,
+}
+
+fn function_type() -> String {
+    "function".to_owned()
+}
+
+#[derive(Deserialize)]
+struct CompletionResponse {
+    choices: Vec<ResponseChoice>,
+}
+
+#[derive(Deserialize)]
+struct ResponseChoice {
+    message: ResponseMessage,
+}
+
+#[derive(Deserialize)]
+struct ResponseMessage {
+    content: Option<String>,
+    #[serde(default)]
+    tool_calls: Vec<WireToolCall>,
+}
+
+/// One server-sent event of a streamed completion.
+#[derive(Deserialize)]
+struct StreamChunk {
+    #[serde(default)]
+    choices: Vec<StreamChoice>,
+}
+
+#[derive(Deserialize)]
+struct StreamChoice {
+    delta: StreamDelta,
+}
+
+#[derive(Deserialize)]
+struct StreamDelta {
+    content: Option<String>,
+    #[serde(default)]
+    tool_calls: Vec<ToolCallDelta>,
+}
+
+/// A piece of a tool call. The first piece of each call carries its id and name, and the
+/// arguments arrive as fragments of the JSON string, all with the same `index`.
+#[derive(Deserialize)]
+struct ToolCallDelta {
+    index: usize,
+    id: Option<String>,
+    function: Option<FunctionCallDelta>,
+}
+
+#[derive(Deserialize)]
+struct FunctionCallDelta {
+    name: Option<String>,
+    arguments: Option<String>,
+}
+
+impl CompatibleBackend {
+    pub fn new(url: String, key: Option<String>, parameters: ModelParameters) -> Self {
+        Self {
+            client: Client::new(),
+            url: url.trim_end_matches('/').to_owned(),
+            key,
+            parameters,
+        }
+    }
+
+    fn request(
+        &self,
+        messages: &[ChatMessage],
+        tools: &[ToolDefinition],
+        stream: bool,
+    ) -> reqwest::RequestBuilder {
+        let request = CompletionRequest {
+            model: &self.parameters.model,
+            messages: messages.iter().map(to_request_message).collect(),
+            tools: tools
+                .iter()
+                .map(|tool| RequestTool {
+                    kind: "function",
+                    function: RequestFunction {
+                        name: tool.name,
+                        description: tool.description,
+                        parameters: &tool.parameters,
+                    },
+                })
+                .collect(),
+            temperature: self.parameters.temperature,
+            top_p: self.parameters.top_p,
+            max_tokens: self.parameters.max_tokens,
+            stream,
+        };
+
+        let mut builder = self
+            .client
+            .post(format!("{}/chat/completions", self.url))
+            .json(&request);
+        if let Some(key) = &self.key {
+            builder = builder.bearer_auth(key);
+        }
+
+        builder
+    }
+}
+
+fn to_request_message(message: &ChatMessage) -> RequestMessage {
+    RequestMessage {
+        role: message.role.as_str(),
+        content: &message.content,
+        tool_calls: message
+            .tool_calls
+            .iter()
+            .map(|call| WireToolCall {
+                id: call.id.clone(),
+                kind: function_type(),
+                function: WireFunctionCall {
+                    name: call.name.clone(),
+                    arguments: call.arguments.clone(),
+                },
+            })
+            .collect(),
+        tool_call_id: match message.role {
+            ChatRole::Tool => message.tool_call_id.as_deref(),
+            _ => None,
+        },
+    }
+}
+
+#[async_trait(?Send)]
+impl ChatBackend for CompatibleBackend {
+    async fn complete(
+        &self,
+        messages: &[ChatMessage],
+        tools: &[ToolDefinition],
+    ) -> Result<Completion, SourcemaptError> {
+        let response = self
+            .request(messages, tools, false)
+            .send()
+            .await?
+            .error_for_status()?
+            .json::<CompletionResponse>()
+            .await?;
+
+        let choice = response
+            .choices
+            .into_iter()
+            .next()
+            .ok_or_else(|| SourcemaptError::Llm("completion has no choices".to_owned()))?;
+
+        Ok(Completion {
+            content: choice.message.content.unwrap_or_default(),
+            tool_calls: choice
+                .message
+                .tool_calls
+                .into_iter()
+                .map(|call| ToolCall {
+                    id: call.id,
+                    name: call.function.name,
+                    arguments: call.function.arguments,
+                })
+                .collect(),
+        })
+    }
+
+    /// Text deltas are passed to `on_text` as they arrive. Tool calls are assembled from their
+    /// deltas and only returned once the stream ends, since their arguments aren't valid JSON
+    /// before that.
+    async fn stream(
+        &self,
+        messages: &[ChatMessage],
+        tools: &[ToolDefinition],
+        on_text: &mut (dyn FnMut(&str) + '_),
+    ) -> Result<Completion, SourcemaptError> {
+        let mut response = self
+            .request(messages, tools, true)
+            .send()
+            .await?
+            .error_for_status()?;
+
+        let mut content = String::new();
+        let mut tool_calls: Vec<ToolCall> = Vec::new();
+        let mut buffer = Vec::new();
+
+        'events: while let Some(bytes) = response.chunk().await? {
+            buffer.extend_from_slice(&bytes);
+
+            // Events are "data: <json>" lines; a chunk can end in the middle of one
+            while let Some(end) = buffer.iter().position(|b| *b == b'\n') {
+                let line = buffer.drain(..=end).collect::<Vec<u8>>();
+                let line = String::from_utf8_lossy(&line);
+                let data = match line.trim().strip_prefix("data:") {
+                    Some(data) => data.trim(),
+                    None => continue,
+                };
+                if data == "[DONE]" {
+                    break 'events;
+                }
+
+                let chunk = serde_json::from_str::<StreamChunk>(data)?;
+                for choice in chunk.choices {
+                    if let Some(text) = choice.delta.content {
+                        on_text(&text);
+                        content.push_str(&text);
+                    }
+
+                    for delta in choice.delta.tool_calls {
+                        while tool_calls.len() <= delta.index {
+                            tool_calls.push(ToolCall {
+                                id: String::new(),
+                                name: String::new(),
+                                arguments: String::new(),
+                            });
+                        }
+                        let call = &mut tool_calls[delta.index];
+
+                        if let Some(id) = delta.id {
+                            call.id = id;
+                        }
+                        if let Some(function) = delta.function {
+                            if let Some(name) = function.name {
+                                call.name.push_str(&name);
+                            }
+                            if let Some(arguments) = function.arguments {
+                                call.arguments.push_str(&arguments);
+                            }
+                        }
+                    }
+                }
+            }
+        }
+
+        Ok(Completion {
+            content,
+            tool_calls,
+        })
+    }
+
+    fn supports_tools(&self) -> bool {
+        true
+    }
+}
@@ -0,0 +1,53 @@
+use crate::error::SourcemaptError;
+use crate::llm::{ChatBackend, ChatMessage, Completion, ToolDefinition};
+use async_trait::async_trait;
+use std::cell::RefCell;
+use std::collections::VecDeque;
+
+/// Returns a fixed sequence of completions, one per call, regardless of the prompt.
+pub struct ScriptedBackend {
+    completions: RefCell<VecDeque<Completion>>,
+}
+
+impl ScriptedBackend {
+    pub fn new<I, S>(completions: I) -> Self
+        where
+            I: IntoIterator<Item=S>,
+            S: Into<String>,
+    {
+        Self::from_completions(completions.into_iter().map(|v| Completion {
+            content: v.into(),
+            tool_calls: Vec::new(),
+        }))
+    }
+
+    /// Like `new`, for completions that also call tools.
+    pub fn from_completions<I>(completions: I) -> Self
+        where
+            I: IntoIterator<Item=Completion>,
+    {
+        Self {
+            completions: RefCell::new(completions.into_iter().collect()),
+        }
+    }
+}
+
+#[async_trait(?Send)]
+impl ChatBackend for ScriptedBackend {
+    async fn complete(
+        &self,
+        _messages: &[ChatMessage],
+        _tools: &[ToolDefinition],
+    ) -> Result<Completion, SourcemaptError> {
+        self.completions
+            .borrow_mut()
+            .pop_front()
+            .ok_or_else(|| {
+                SourcemaptError::Llm("scripted backend has no completions left".to_owned())
+            })
+    }
+
+    fn supports_tools(&self) -> bool {
+        true
+    }
+}
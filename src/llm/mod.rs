@@ -0,0 +1,189 @@
+pub mod compatible;
+pub mod mock;
+pub mod openai;
+
+use crate::config::{Config, ConfigError, LlmConfig};
+use crate::error::SourcemaptError;
+use crate::llm::compatible::CompatibleBackend;
+use crate::llm::mock::ScriptedBackend;
+use crate::llm::openai::OpenAiBackend;
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+
+const OPENAI_API_URL: &str = "https://api.openai.com/v1";
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ChatRole {
+    System,
+    User,
+    Assistant,
+    Tool,
+}
+
+impl ChatRole {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ChatRole::System => "system",
+            ChatRole::User => "user",
+            ChatRole::Assistant => "assistant",
+            ChatRole::Tool => "tool",
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ChatMessage {
+    pub role: ChatRole,
+    pub content: String,
+    /// Tools the assistant asked to call in this message.
+    pub tool_calls: Vec<ToolCall>,
+    /// For `Tool` messages, the call this is the result of.
+    pub tool_call_id: Option<String>,
+}
+
+impl ChatMessage {
+    pub fn new(role: ChatRole, content: String) -> Self {
+        Self {
+            role,
+            content,
+            tool_calls: Vec::new(),
+            tool_call_id: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ToolCall {
+    pub id: String,
+    pub name: String,
+    /// JSON-encoded arguments, exactly as produced by the model.
+    pub arguments: String,
+}
+
+/// A function the model may call, described by a JSON schema for its arguments.
+#[derive(Debug, Clone)]
+pub struct ToolDefinition {
+    pub name: &'static str,
+    pub description: &'static str,
+    pub parameters: serde_json::Value,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Completion {
+    pub content: String,
+    pub tool_calls: Vec<ToolCall>,
+}
+
+/// How commands are exchanged with the model.
+#[derive(Debug, Clone, Copy, PartialEq, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Protocol {
+    /// `!COMMAND "arg"` lines in the model's text, described in the system prompt.
+    #[default]
+    Text,
+    /// The OpenAI tools/function-calling API.
+    Tools,
+}
+
+#[derive(Debug, Clone)]
+pub struct ModelParameters {
+    pub model: String,
+    pub temperature: Option<f32>,
+    pub top_p: Option<f32>,
+    pub max_tokens: Option<u32>,
+}
+
+impl Default for ModelParameters {
+    fn default() -> Self {
+        Self {
+            model: "gpt-4".to_owned(),
+            temperature: None,
+            top_p: Some(0.1),
+            max_tokens: Some(512),
+        }
+    }
+}
+
+impl From<&LlmConfig> for ModelParameters {
+    /// Any parameter missing from the config keeps its default.
+    fn from(config: &LlmConfig) -> Self {
+        let defaults = Self::default();
+
+        Self {
+            model: config.model.clone().unwrap_or(defaults.model),
+            temperature: config.temperature.or(defaults.temperature),
+            top_p: config.top_p.or(defaults.top_p),
+            max_tokens: config.max_tokens.or(defaults.max_tokens),
+        }
+    }
+}
+
+/// A chat model that `Sourcemapt` can send its prompt history to.
+#[async_trait(?Send)]
+pub trait ChatBackend {
+    /// `tools` is only non-empty for backends that return true from `supports_tools`.
+    async fn complete(
+        &self,
+        messages: &[ChatMessage],
+        tools: &[ToolDefinition],
+    ) -> Result<Completion, SourcemaptError>;
+
+    /// Like `complete`, but calls `on_text` with each piece of the content as it arrives.
+    /// Backends that can't stream call it once with the whole content.
+    async fn stream(
+        &self,
+        messages: &[ChatMessage],
+        tools: &[ToolDefinition],
+        on_text: &mut (dyn FnMut(&str) + '_),
+    ) -> Result<Completion, SourcemaptError> {
+        let completion = self.complete(messages, tools).await?;
+        on_text(&completion.content);
+        Ok(completion)
+    }
+
+    fn supports_tools(&self) -> bool {
+        false
+    }
+}
+
+/// Builds the backend selected by `llm.backend`: `openai` (the default), `compatible` or
+/// `scripted`.
+pub fn from_config(config: &Config) -> Result<Box<dyn ChatBackend>, ConfigError> {
+    let llm = &config.llm;
+
+    match llm.backend.as_str() {
+        "openai" => {
+            let key = config.llm_key().ok_or_else(|| {
+                ConfigError::Invalid(
+                    "Set the 'llm.key' value in the configuration file before using the openai backend".to_owned(),
+                )
+            })?;
+
+            // openai_dive has no tools support, but the OpenAI API is itself OpenAI-compatible
+            if llm.protocol == Protocol::Tools {
+                return Ok(Box::new(CompatibleBackend::new(
+                    OPENAI_API_URL.to_owned(),
+                    Some(key.to_owned()),
+                    llm.into(),
+                )));
+            }
+
+            Ok(Box::new(OpenAiBackend::new(key.to_owned(), llm.into())))
+        }
+        "compatible" => {
+            let url = llm.url.as_ref().ok_or_else(|| {
+                ConfigError::Invalid(
+                    "Set 'llm.url' in the configuration file to use the compatible backend".to_owned(),
+                )
+            })?;
+
+            Ok(Box::new(CompatibleBackend::new(
+                url.clone(),
+                config.llm_key().map(|v| v.to_owned()),
+                llm.into(),
+            )))
+        }
+        "scripted" => Ok(Box::new(ScriptedBackend::new(llm.completions.clone()))),
+        backend => Err(ConfigError::Invalid(format!("Unknown backend: {}", backend))),
+    }
+}
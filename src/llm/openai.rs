@@ -0,0 +1,106 @@
+use crate::error::SourcemaptError;
+use crate::llm::{ChatBackend, ChatMessage, ChatRole, Completion, ModelParameters, ToolDefinition};
+use async_trait::async_trait;
+use futures::StreamExt;
+use openai_dive::v1::api::Client;
+use openai_dive::v1::resources::chat_completion::{ChatCompletionParameters, Role};
+
+pub struct OpenAiBackend {
+    client: Client,
+    parameters: ModelParameters,
+}
+
+impl OpenAiBackend {
+    pub fn new(api_key: String, parameters: ModelParameters) -> Self {
+        Self {
+            client: Client::new(api_key),
+            parameters,
+        }
+    }
+
+    fn completion_parameters(&self, messages: &[ChatMessage]) -> ChatCompletionParameters {
+        let messages = messages
+            .iter()
+            .map(|message| openai_dive::v1::resources::chat_completion::ChatMessage {
+                role: match message.role {
+                    ChatRole::System => Role::System,
+                    ChatRole::User => Role::User,
+                    ChatRole::Assistant => Role::Assistant,
+                    // Tool results are only produced when tools are supported
+                    ChatRole::Tool => Role::User,
+                },
+                content: message.content.clone(),
+                name: None,
+            })
+            .collect();
+
+        ChatCompletionParameters {
+            model: self.parameters.model.clone(),
+            messages,
+            temperature: self.parameters.temperature,
+            top_p: self.parameters.top_p,
+            n: None,
+            stop: None,
+            max_tokens: self.parameters.max_tokens,
+            presence_penalty: None,
+            frequency_penalty: None,
+            logit_bias: None,
+        }
+    }
+}
+
+#[async_trait(?Send)]
+impl ChatBackend for OpenAiBackend {
+    async fn complete(
+        &self,
+        messages: &[ChatMessage],
+        _tools: &[ToolDefinition],
+    ) -> Result<Completion, SourcemaptError> {
+        let completion = self
+            .client
+            .chat()
+            .create(self.completion_parameters(messages))
+            .await
+            .map_err(|e| SourcemaptError::Llm(e.to_string()))?;
+        let choice = completion
+            .choices
+            .first()
+            .ok_or_else(|| SourcemaptError::Llm("completion has no choices".to_owned()))?;
+
+        Ok(Completion {
+            content: choice.message.content.clone(),
+            tool_calls: Vec::new(),
+        })
+    }
+
+    async fn stream(
+        &self,
+        messages: &[ChatMessage],
+        _tools: &[ToolDefinition],
+        on_text: &mut (dyn FnMut(&str) + '_),
+    ) -> Result<Completion, SourcemaptError> {
+        let mut chunks = self
+            .client
+            .chat()
+            .create_stream(self.completion_parameters(messages))
+            .await
+            .map_err(|e| SourcemaptError::Llm(e.to_string()))?;
+
+        let mut content = String::new();
+        while let Some(chunk) = chunks.next().await {
+            let chunk = chunk.map_err(|e| SourcemaptError::Llm(e.to_string()))?;
+
+            for choice in chunk.choices {
+                if let Some(text) = choice.delta.content {
+                    on_text(&text);
+                    content.push_str(&text);
+                }
+            }
+        }
+
+        Ok(Completion {
+            content,
+            tool_calls: Vec::new(),
+        })
+    }
+}
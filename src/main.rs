@@ -1,75 +1,327 @@
 extern crate core;
 
+mod cli;
+mod code_host;
+mod config;
 mod consts;
+mod error;
+mod filter;
+mod llm;
 
 #[macro_use]
 mod util;
 
 mod message;
+mod repl;
+mod session;
 mod sourcegraph;
 mod types;
 
+#[cfg(test)]
+mod tests;
+
+use crate::cli::CliCommand;
+use crate::code_host::CodeHost;
+use crate::config::{BudgetConfig, Config, ConfigError};
+use crate::error::SourcemaptError;
+use crate::filter::Pipeline;
+use crate::llm::{ChatBackend, ChatMessage, ChatRole, Completion, Protocol, ToolDefinition};
 use crate::message::SourcemaptMessage;
-use crate::sourcegraph::client::SourcegraphClient;
+use crate::session::Session;
+use crate::sourcegraph::definition_and_hover::DefinitionNode;
 use crate::types::{CodeBlock, Command, InjectedMessage};
 use crossterm::queue;
-use openai_dive::v1::api::Client;
-use openai_dive::v1::resources::chat_completion::ChatCompletionParameters;
-use std::error::Error;
-use std::fs::File;
-use std::io::Write;
-use std::{fs, process};
-use toml::Value;
+use futures::channel::mpsc::{self, UnboundedSender};
+use futures::future;
+use futures::stream::{self, StreamExt};
+use std::collections::VecDeque;
+use std::io::{self, Write};
+use std::path::PathBuf;
+use std::process;
+
+/// Messages at the end of the history that compaction never hides, so the model keeps the
+/// context of the latest exchange.
+const KEEP_RECENT_MESSAGES: usize = 6;
 
-// TODO: Side analyzer to strip licenses, irrelevant comments, etc. from GET_LINES to save tokens
+/// Definitions of one symbol whose lines `!JUMP` reads; any others are only listed.
+const MAX_JUMP_DEFINITIONS: usize = 5;
 
-// TODO: "Decision" system which keeps track of a set of shown data which can all be hidden when
-// the AI makes a decision
-// For example, let the AI traverse the directory tree to find files it might want to look at
-// These messages don't need to be kept once the AI decides what file it wants to see
+/// Name of the scope that starts implicitly after a question or a decision.
+const DEFAULT_SCOPE: &str = "the repository";
 
 #[tokio::main]
 async fn main() {
-    let api_key = read_or_create_config().unwrap();
-
-    let mut sourcemapt = Sourcemapt::new(
-        api_key,
-        "github.com/kubernetes/kubernetes".to_owned(),
-        "master".to_owned(),
-    );
-    sourcemapt.add_system();
-
-    let def = sourcemapt.sourcegraph_client.get_definition(
-        "github.com/kubernetes/kubezrnetes",
-        "master",
-        "test/e2e/framework/pod/wait.go",
-        433,
-        24,
-    ).await.unwrap_or_default();
-
-    match sourcemapt.run_loop().await {
-        None => {}
-        Some(e) => {
-            eprintln!("Error: {}", e);
+    let cli = cli::parse();
+
+    let config = match Config::load(cli.profile.as_deref()) {
+        Ok(config) => config,
+        Err(ConfigError::Created(path)) => {
+            print_success!("{}", ConfigError::Created(path));
+            process::exit(1);
+        }
+        Err(e) => {
+            print_error!("Failed to load configuration: {}", e);
             process::exit(1);
         }
+    };
+
+    match cli.command {
+        CliCommand::Ask { repo, rev, local, question, interactive } => {
+            let (backend, code_host) = build_clients(&config, local);
+            let (repo, rev) = config.defaults.resolve(repo, rev);
+
+            let mut sourcemapt = Sourcemapt::new(backend, code_host, repo, rev);
+            sourcemapt.budget = config.budget.clone();
+            sourcemapt.filters = filter::from_config(&config.filters);
+            sourcemapt.protocol = protocol(&config, sourcemapt.backend.as_ref());
+            sourcemapt.session_id = Some(Session::new_id());
+            sourcemapt.add_system();
+
+            if let Err(e) = sourcemapt.pin_revision().await {
+                print_error!("Failed to resolve {}@{}: {}", sourcemapt.repo, sourcemapt.refspec, e);
+                process::exit(1);
+            }
+
+            let result = match question {
+                Some(question) => sourcemapt.run_loop(question).await,
+                None => None,
+            };
+
+            finish(sourcemapt, result, interactive, &config).await;
+        }
+        CliCommand::Resume { id, local, question, interactive } => {
+            let session = match Session::load(&id) {
+                Ok(session) => session,
+                Err(e) => {
+                    print_error!("{}", e);
+                    process::exit(1);
+                }
+            };
+
+            let (backend, code_host) = build_clients(&config, local);
+
+            let mut sourcemapt = Sourcemapt::from_session(backend, code_host, session);
+            sourcemapt.budget = config.budget.clone();
+            sourcemapt.filters = filter::from_config(&config.filters);
+            sourcemapt.protocol = protocol(&config, sourcemapt.backend.as_ref());
+            print_success!("Resuming session {} on {}@{}", id, sourcemapt.repo, sourcemapt.refspec);
+
+            // Sessions saved before revisions were pinned are pinned now
+            if sourcemapt.commit_oid.is_none() {
+                if let Err(e) = sourcemapt.pin_revision().await {
+                    print_error!("Failed to resolve {}@{}: {}", sourcemapt.repo, sourcemapt.refspec, e);
+                    process::exit(1);
+                }
+            }
+
+            let result = match question {
+                Some(question) => sourcemapt.run_loop(question).await,
+                None => sourcemapt.resume_loop().await,
+            };
+
+            finish(sourcemapt, result, interactive, &config).await;
+        }
+        CliCommand::ListSessions => {
+            let sessions = match Session::list() {
+                Ok(sessions) => sessions,
+                Err(e) => {
+                    print_error!("Failed to list sessions: {}", e);
+                    process::exit(1);
+                }
+            };
+
+            for session in sessions {
+                match &session.commit_oid {
+                    Some(oid) => println!("{}  {}@{} ({})", session.id, session.repo, session.refspec, oid),
+                    None => println!("{}  {}@{}", session.id, session.repo, session.refspec),
+                }
+                println!("  Q: {}", first_line(session.question().unwrap_or("(no question)")));
+                println!("  A: {}", first_line(session.summary().unwrap_or("(unfinished)")));
+            }
+        }
     }
+}
 
-    let _ = def;
+fn build_clients(config: &Config, local: Option<PathBuf>) -> (Box<dyn ChatBackend>, Box<dyn CodeHost>) {
+    let backend = llm::from_config(config);
+    let code_host = code_host::from_config(config, local);
 
-    for message in sourcemapt.messages {
-        println!("{}", message);
+    match (backend, code_host) {
+        (Ok(backend), Ok(code_host)) => (backend, code_host),
+        (Err(e), _) | (_, Err(e)) => {
+            print_error!("{}", e);
+            process::exit(1);
+        }
+    }
+}
+
+fn protocol(config: &Config, backend: &dyn ChatBackend) -> Protocol {
+    if config.llm.protocol == Protocol::Tools && !backend.supports_tools() {
+        print_error!("The {} backend doesn't support tools, using the text protocol", config.llm.backend);
+        return Protocol::Text;
+    }
+
+    config.llm.protocol
+}
+
+/// Reports the outcome of the first question, then either hands over to the REPL or prints the
+/// history and exits.
+async fn finish(
+    mut sourcemapt: Sourcemapt,
+    result: Option<SourcemaptError>,
+    interactive: bool,
+    config: &Config,
+) {
+    if let Some(e) = result {
+        eprintln!("Error: {}", e);
+        if !interactive {
+            process::exit(1);
+        }
+    }
+
+    if interactive {
+        repl::run(&mut sourcemapt).await;
+        return;
+    }
+
+    if config.output.print_history {
+        for message in &sourcemapt.messages {
+            if message.hidden() && !config.output.show_hidden { continue; }
+            println!("{}", message);
+        }
+    }
+
+    if let Some(oid) = &sourcemapt.commit_oid {
+        print_success!("Answered from {}@{} ({})", sourcemapt.repo, oid, sourcemapt.refspec);
+    }
+
+    if let Some(id) = &sourcemapt.session_id {
+        print_success!("Session saved as {}", id);
+    }
+}
+
+fn first_line(text: &str) -> &str {
+    text.lines().find(|v| !v.trim().is_empty()).unwrap_or("").trim()
+}
+
+/// Sends the command on `line`, if there is one that can run early, to be executed while the rest
+/// of the response streams in.
+fn dispatch_line(line: &str, sender: &UnboundedSender<Command>) {
+    let line = line.trim_end_matches(['\r', '\n']);
+    if !Command::match_line(line) {
+        return;
+    }
+
+    // Lines that fail to parse are reported once the whole response is parsed
+    if let Ok(command) = line.parse::<Command>() {
+        if command.is_read_only() {
+            let _ = sender.unbounded_send(command);
+        }
+    }
+}
+
+/// Lists every definition a `!JUMP` found, followed by the lines read from the first
+/// `MAX_JUMP_DEFINITIONS` of them, or why they couldn't be read.
+fn format_definitions(
+    repo: &str,
+    definitions: &[DefinitionNode],
+    hover: Option<String>,
+    blocks: &[Result<CodeBlock, SourcemaptError>],
+) -> String {
+    let mut sections = Vec::new();
+
+    if let Some(hover) = hover {
+        sections.push(hover.trim().to_owned());
+    }
+
+    let list = definitions
+        .iter()
+        .enumerate()
+        .map(|(i, def)| {
+            let location = format!(
+                "{}. {} {}:{}",
+                i + 1,
+                def.resource.repo,
+                def.resource.path,
+                def.range.line_start + 1
+            );
+            if def.resource.repo != repo {
+                format!("{} (in another repository)", location)
+            } else {
+                location
+            }
+        })
+        .collect::<Vec<String>>()
+        .join("\n");
+    let noun = if definitions.len() == 1 { "definition" } else { "definitions" };
+    sections.push(format!("Found {} {}:\n{}", definitions.len(), noun, list));
+
+    for (i, (def, block)) in definitions.iter().zip(blocks).enumerate() {
+        sections.push(match block {
+            Ok(code) => format!("Definition {} ({}):\n{}", i + 1, code.path, code.format()),
+            Err(e) => format!(
+                "Definition {}: {}:{} (unavailable: {})",
+                i + 1,
+                def.resource.repo,
+                def.resource.path,
+                e
+            ),
+        });
+    }
+
+    if blocks.len() < definitions.len() {
+        sections.push(format!("Only the first {} definitions are shown.", blocks.len()));
+    }
+
+    sections.join("\n\n")
+}
+
+/// What the model sees in place of the output of a command that failed, so it can correct the
+/// command instead of the session ending.
+fn command_error(command: &Command, error: &SourcemaptError, call_id: Option<String>) -> SourcemaptMessage {
+    let content = serde_json::json!({
+        "error": error.to_string(),
+        "command": command.serialize(),
+    });
+
+    SourcemaptMessage::CommandResult {
+        content: content.to_string(),
+        hidden: false,
+        call_id,
     }
 }
 
 struct Sourcemapt {
-    openai_client: Client,
-    sourcegraph_client: SourcegraphClient,
+    backend: Box<dyn ChatBackend>,
+    code_host: Box<dyn CodeHost>,
 
     messages: Vec<SourcemaptMessage>,
 
     repo: String,
     refspec: String,
+    /// What `refspec` resolved to when the session started. Every read is pinned to it, so the
+    /// answer doesn't mix commits if the branch moves.
+    commit_oid: Option<String>,
+
+    budget: BudgetConfig,
+    /// Elides boilerplate from code before it's shown to the model.
+    filters: Pipeline,
+    protocol: Protocol,
+
+    /// Commands that have failed since the last one that succeeded.
+    consecutive_errors: usize,
+    /// Commands from the latest response that already ran, in order, waiting to be processed.
+    dispatched: VecDeque<DispatchedCommand>,
+
+    /// Where the session is saved to after every turn; not persisted when unset.
+    session_id: Option<String>,
+    created: u64,
+}
+
+/// A command that was run while the response it came from was still streaming.
+struct DispatchedCommand {
+    command: Command,
+    result: Result<SourcemaptMessage, SourcemaptError>,
 }
 
 enum ProcessResponsesOutcome {
@@ -79,40 +331,167 @@ enum ProcessResponsesOutcome {
 }
 
 impl Sourcemapt {
-    fn new(api_key: String, repo: String, refspec: String) -> Self {
+    fn new(
+        backend: Box<dyn ChatBackend>,
+        code_host: Box<dyn CodeHost>,
+        repo: String,
+        refspec: String,
+    ) -> Self {
         Self {
-            openai_client: Client::new(api_key),
-            sourcegraph_client: SourcegraphClient::new(),
+            backend,
+            code_host,
             messages: Vec::new(),
             repo: repo,
             refspec: refspec,
+            commit_oid: None,
+            budget: BudgetConfig::default(),
+            filters: Pipeline::default(),
+            protocol: Protocol::Text,
+            consecutive_errors: 0,
+            dispatched: VecDeque::new(),
+            session_id: None,
+            created: Session::now(),
         }
     }
 
+    fn from_session(
+        backend: Box<dyn ChatBackend>,
+        code_host: Box<dyn CodeHost>,
+        session: Session,
+    ) -> Self {
+        let mut sourcemapt = Self::new(backend, code_host, session.repo, session.refspec);
+        sourcemapt.messages = session.messages;
+        sourcemapt.commit_oid = session.commit_oid;
+        sourcemapt.session_id = Some(session.id);
+        sourcemapt.created = session.created;
+        sourcemapt
+    }
+
+    fn save_session(&self) {
+        let id = match &self.session_id {
+            Some(id) => id,
+            None => return,
+        };
+
+        let session = Session {
+            id: id.clone(),
+            created: self.created,
+            repo: self.repo.clone(),
+            refspec: self.refspec.clone(),
+            commit_oid: self.commit_oid.clone(),
+            messages: self.messages.clone(),
+        };
+
+        if let Err(e) = session.save() {
+            print_error!("Failed to save session {}: {}", id, e);
+        }
+    }
+
+    /// Resolves `refspec` and pins every later read to the commit it points to now.
+    async fn pin_revision(&mut self) -> Result<(), SourcemaptError> {
+        let oid = self.code_host.resolve_revision(&self.repo, &self.refspec).await?;
+        print_success!("Pinned {}@{} to {}", self.repo, self.refspec, oid);
+        self.commit_oid = Some(oid);
+        Ok(())
+    }
+
+    /// The revision reads are made at: the pinned commit, or `refspec` if it hasn't been resolved.
+    fn rev(&self) -> &str {
+        self.commit_oid.as_deref().unwrap_or(&self.refspec)
+    }
+
     fn add_system(&mut self) {
+        let content = match self.protocol {
+            Protocol::Text => consts::SYSTEM,
+            Protocol::Tools => consts::SYSTEM_TOOLS,
+        };
+
         self.messages.push(SourcemaptMessage::System {
-            content: consts::SYSTEM.trim().to_owned(),
+            content: content.trim().to_owned(),
             hidden: false,
         });
     }
 
-    async fn run_loop(&mut self) -> Option<Box<dyn Error>> {
-        let mut responses;
+    /// Starts a new conversation, saved as a new session so the previous one stays resumable.
+    fn reset(&mut self) {
+        self.messages.clear();
+        self.session_id = Some(Session::new_id());
+        self.created = Session::now();
+        self.add_system();
+    }
+
+    async fn run_loop(&mut self, question: String) -> Option<SourcemaptError> {
+        self.consecutive_errors = 0;
 
-        responses = self.call_gpt4(
+        let responses = match self.call_gpt4(
             &vec![SourcemaptMessage::User {
-                // content: "I'm interested to know how the kubelet volume manager determines whether reconciler states have been synced. What is some relevant code?".to_owned(),
-                content: "What is the purpose of the `endpoints.RepackSubsets(subsets)` function call in the Endpoints Controller syncService, and how does it affect the resulting `subsets`?".to_owned(),
+                content: question,
                 hidden: false,
             }],
-        ).await.ok()?.to_vec();
+        ).await {
+            Ok(responses) => responses.to_vec(),
+            Err(e) => return Some(e),
+        };
+
+        self.drive(responses).await
+    }
+
+    /// Picks an interrupted investigation back up: model responses whose commands never ran are
+    /// processed again, and if the model hadn't replied yet it is called with the history as is.
+    async fn resume_loop(&mut self) -> Option<SourcemaptError> {
+        let start = self.messages
+            .iter()
+            .rposition(|v| !matches!(
+                v,
+                SourcemaptMessage::Model { .. } | SourcemaptMessage::CommandInvocation { .. }
+            ))
+            .map_or(0, |i| i + 1);
+
+        let responses = if start < self.messages.len() {
+            self.messages[start..].to_vec()
+        } else {
+            match self.call_gpt4(&[]).await {
+                Ok(responses) => responses.to_vec(),
+                Err(e) => return Some(e),
+            }
+        };
+
+        self.drive(responses).await
+    }
+
+    async fn drive(&mut self, mut responses: Vec<SourcemaptMessage>) -> Option<SourcemaptError> {
+        let mut turns = 1;
 
         loop {
+            self.save_session();
+
+            // The model's text has already been streamed to the terminal
             for response in &responses {
-                println!("{}", response);
+                if let SourcemaptMessage::CommandInvocation { .. } = response {
+                    println!("{}", response);
+                }
             }
 
-            let result = self.process_responses(&responses).await.ok()?;
+            let result = match self.process_responses(&responses).await {
+                Ok(result) => result,
+                Err(e) => {
+                    self.save_session();
+                    return Some(e);
+                }
+            };
+
+            if turns >= self.budget.max_turns && !matches!(result, ProcessResponsesOutcome::Stop) {
+                // The commands already ran, and their tool calls can't be left without results
+                if let ProcessResponsesOutcome::CallWithCommandResults(results) = result {
+                    self.messages.extend(results);
+                    self.save_session();
+                }
+                return Some(SourcemaptError::Budget(format!(
+                    "Gave up after {} model calls without a summary",
+                    turns
+                )));
+            }
+            turns += 1;
 
             match result {
                 ProcessResponsesOutcome::CallForIntrospect => {
@@ -127,64 +506,59 @@ impl Sourcemapt {
                     //     }
                     // ]).await.unwrap().to_vec();
 
-                    responses = self.call_gpt4(&vec![
+                    responses = match self.call_gpt4(&vec![
                         SourcemaptMessage::Injected {
                             kind: InjectedMessage::AskToSummarize,
                             hidden: false,
                         }
-                    ]).await.unwrap().to_vec();
+                    ]).await {
+                        Ok(responses) => responses.to_vec(),
+                        Err(e) => return Some(e),
+                    };
                 }
                 ProcessResponsesOutcome::CallWithCommandResults(results) => {
                     print_success!("-> Outcome: Call with command results:");
                     for result in &results {
                         print_success!("| {}", result);
                     }
-                    responses = self.call_gpt4(&results).await.unwrap().to_vec();
+                    responses = match self.call_gpt4(&results).await {
+                        Ok(responses) => responses.to_vec(),
+                        Err(e) => return Some(e),
+                    };
                 }
                 ProcessResponsesOutcome::Stop => {
                     print_success!("-> Outcome: Stop");
                     return None;
                 }
             }
-
-            self.compact();
         }
     }
 
     async fn call_gpt4(
         &mut self,
         messages: &[SourcemaptMessage],
-    ) -> Result<&[SourcemaptMessage], Box<dyn Error>> {
+    ) -> Result<&[SourcemaptMessage], SourcemaptError> {
         for message in messages {
             self.messages.push(message.clone());
         }
 
-        let mut prompt_messages = Vec::new();
-
-        for hist_message in &self.messages {
-            if hist_message.hidden() { continue; }
-            prompt_messages.push(hist_message.map_to_chat_message());
-        }
+        // Before the prompt is built, so what's sent stays under the budget even for a resumed
+        // session or a turn with a lot of command output
+        self.compact();
+        let prompt_messages = self.prompt_messages();
 
         let hist_end = self.messages.len();
 
-        //
-
-        let parameters = ChatCompletionParameters {
-            model: "gpt-4".to_owned(),
-            messages: prompt_messages,
-            temperature: None,
-            top_p: Some(0.1),
-            n: None,
-            stop: None,
-            max_tokens: Some(512),
-            presence_penalty: None,
-            frequency_penalty: None,
-            logit_bias: None,
+        let tools = match self.protocol {
+            Protocol::Text => Vec::new(),
+            Protocol::Tools => Command::tool_definitions(),
         };
 
-        let completion_r = self.openai_client.chat().create(parameters).await.unwrap();
-        let completion = completion_r.choices[0].message.content.trim();
+        let (completion, dispatched) = self.stream_completion(&prompt_messages, &tools).await?;
+        self.dispatched = dispatched;
+        let tool_calls = completion.tool_calls;
+        let completion = self.replace_echoed_code(&completion.content);
+        let completion = completion.trim();
 
         // println!("-----");
         // println!("{}", completion);
@@ -208,10 +582,15 @@ impl Sourcemapt {
                     buffer.clear();
                 }
 
-                let command = line.parse::<Command>()?;
+                let command = line.parse::<Command>().unwrap_or_else(|e| Command::Invalid {
+                    text: line.trim().to_owned(),
+                    tool: None,
+                    error: e.to_string(),
+                });
                 self.messages.push(SourcemaptMessage::CommandInvocation {
                     command,
                     hidden: false,
+                    call_id: None,
                 });
 
                 let next_line = completion.lines().next();
@@ -235,98 +614,178 @@ impl Sourcemapt {
             });
         }
 
+        for tool_call in tool_calls {
+            let command = Command::from_tool_call(&tool_call.name, &tool_call.arguments)
+                .unwrap_or_else(|e| Command::Invalid {
+                    text: tool_call.arguments.clone(),
+                    tool: Some(tool_call.name.clone()),
+                    error: e.to_string(),
+                });
+            self.messages.push(SourcemaptMessage::CommandInvocation {
+                command,
+                hidden: false,
+                call_id: Some(tool_call.id),
+            });
+        }
+
         Ok(&self.messages[hist_end..])
     }
 
+    /// The visible history as it's sent to the model.
+    fn prompt_messages(&self) -> Vec<ChatMessage> {
+        let mut prompt_messages: Vec<ChatMessage> = Vec::new();
+
+        for hist_message in &self.messages {
+            if hist_message.hidden() { continue; }
+            let chat_message = hist_message.map_to_chat_message();
+
+            // All tool calls from one turn have to be in a single assistant message, ahead of
+            // their results
+            if !chat_message.tool_calls.is_empty() {
+                if let Some(last) = prompt_messages.last_mut() {
+                    if last.role == ChatRole::Assistant {
+                        last.tool_calls.extend(chat_message.tool_calls);
+                        continue;
+                    }
+                }
+            }
+
+            prompt_messages.push(chat_message);
+        }
+
+        prompt_messages
+    }
+
+    /// Streams the model's response to the terminal. Each read-only command in it is started as
+    /// soon as its line is complete, while the rest of the response arrives.
+    async fn stream_completion(
+        &self,
+        prompt_messages: &[ChatMessage],
+        tools: &[ToolDefinition],
+    ) -> Result<(Completion, VecDeque<DispatchedCommand>), SourcemaptError> {
+        let (sender, receiver) = mpsc::unbounded::<Command>();
+
+        let completion = async move {
+            let mut line = String::new();
+            let mut on_text = |text: &str| {
+                print!("{}", text);
+                let _ = io::stdout().flush();
+
+                line.push_str(text);
+                while let Some(end) = line.find('\n') {
+                    let complete = line.drain(..=end).collect::<String>();
+                    dispatch_line(&complete, &sender);
+                }
+            };
+
+            let completion = self.backend.stream(prompt_messages, tools, &mut on_text).await;
+            dispatch_line(&line, &sender);
+            println!();
+            completion
+        };
+
+        // The invocations join the history after everything in it now
+        let position = self.messages.len();
+        let dispatched = receiver
+            .map(|command| async move {
+                let result = self.execute_command(&command, None, position).await;
+                DispatchedCommand { command, result }
+            })
+            .buffered(self.budget.max_concurrent_commands.max(1))
+            .collect::<VecDeque<_>>();
+
+        let (completion, dispatched) = futures::join!(completion, dispatched);
+        Ok((completion?, dispatched))
+    }
+
+    /// The result of `command` if it was already run while the response was streamed. Commands
+    /// from tool calls aren't dispatched early, as their call ID is only known at the end.
+    fn take_dispatched(
+        &mut self,
+        command: &Command,
+        call_id: &Option<String>,
+    ) -> Option<Result<SourcemaptMessage, SourcemaptError>> {
+        if call_id.is_some() {
+            return None;
+        }
+
+        // Anything dispatched ahead of it didn't survive parsing the full response
+        let i = self.dispatched.iter().position(|v| &v.command == command)?;
+        self.dispatched.drain(..i);
+        self.dispatched.pop_front().map(|v| v.result)
+    }
+
+    /// Replaces fenced blocks in `text` that repeat code already shown in a `Code` message with a
+    /// reference to it, like "(see foo.go:120-140)", so the code isn't sent twice on later turns.
+    /// A summary is left as it is, as it's the answer the user reads.
+    fn replace_echoed_code(&self, text: &str) -> String {
+        if text.contains("IN SUMMARY:") {
+            return text.to_owned();
+        }
+
+        let mut output = Vec::new();
+        let mut lines = text.lines();
+
+        while let Some(line) = lines.next() {
+            if !line.trim_start().starts_with("```") {
+                output.push(line.to_owned());
+                continue;
+            }
+
+            let mut block = Vec::new();
+            let mut closed = false;
+            for inner in lines.by_ref() {
+                if inner.trim() == "```" {
+                    closed = true;
+                    break;
+                }
+                block.push(inner);
+            }
+
+            // A single line is as short as the reference would be
+            let found = if closed && block.iter().filter(|v| !v.trim().is_empty()).count() > 1 {
+                self.messages.iter().rev().find_map(|message| match message {
+                    SourcemaptMessage::Code { code, .. } => {
+                        code.find(&block).map(|(first, last)| format!("(see {}:{}-{})", code.path, first, last))
+                    }
+                    _ => None,
+                })
+            } else {
+                None
+            };
+
+            match found {
+                Some(reference) => {
+                    print_progress!("Replacing repeated code with {}", reference);
+                    output.push(reference);
+                }
+                None => {
+                    output.push(line.to_owned());
+                    output.extend(block.iter().map(|v| v.to_string()));
+                    if closed {
+                        output.push("```".to_owned());
+                    }
+                }
+            }
+        }
+
+        output.join("\n")
+    }
+
     async fn process_responses(
         &mut self,
         responses: &[SourcemaptMessage],
-    ) -> Result<ProcessResponsesOutcome, Box<dyn Error>> {
-        let mut command_results = Vec::new();
-
-        for response in responses {
-            println!("");
+    ) -> Result<ProcessResponsesOutcome, SourcemaptError> {
+        let mut invocations = Vec::new();
+        // The responses are the end of the history
+        let start = self.messages.len() - responses.len();
 
+        for (i, response) in responses.iter().enumerate() {
             match response {
                 SourcemaptMessage::Model { .. } => {}
-                SourcemaptMessage::CommandInvocation { command, .. } => {
-                    match command {
-                        Command::SearchFiles { keywords } => {
-                            let res = self.sourcegraph_client.search_files(
-                                &self.repo,
-                                keywords.as_slice(),
-                            ).await?;
-
-                            let json = serde_json::to_string(&res)?;
-
-                            command_results.push(SourcemaptMessage::CommandResult {
-                                content: json,
-                                hidden: false,
-                            })
-                        }
-                        Command::ReadLines { file, start, n } => {
-                            let content = self.sourcegraph_client.get_file_content(
-                                &self.repo,
-                                &self.refspec,
-                                file,
-                            ).await?.content;
-
-                            let lines = content.lines()
-                                .skip(*start)
-                                .take(*n)
-                                .map(|v| v.to_owned())
-                                .collect::<Vec<String>>();
-
-                            command_results.push(SourcemaptMessage::Code {
-                                code: CodeBlock {
-                                    lines: lines,
-                                    start: *start,
-                                },
-                                hidden: false,
-                            });
-                        }
-                        Command::Jump { file, line, char, n } => {
-                            let definition_result = self.sourcegraph_client.get_definition(
-                                &self.repo,
-                                &self.refspec,
-                                file,
-                                *line as u32,
-                                *char as u32,
-                            ).await?;
-
-                            match definition_result {
-                                None => {
-                                    command_results.push(SourcemaptMessage::User {
-                                        content: format!("Couldn't find definition for `{}`", file),
-                                        hidden: false,
-                                    });
-                                }
-                                Some(v) => {
-                                    let def = v.definitions.first().unwrap();
-
-                                    let content = self.sourcegraph_client.get_file_content(
-                                        &def.resource.repo,
-                                        &def.resource.commit_oid,
-                                        &def.resource.path,
-                                    ).await?.content;
-
-                                    let lines = content.lines()
-                                        .skip(def.range.line_start as usize)
-                                        .take(*n)
-                                        .map(|v| v.to_owned())
-                                        .collect::<Vec<String>>();
-
-                                    command_results.push(SourcemaptMessage::Code {
-                                        code: CodeBlock {
-                                            lines: lines,
-                                            start: def.range.line_start as usize,
-                                        },
-                                        hidden: false,
-                                    });
-                                }
-                            }
-                        }
-                    }
+                SourcemaptMessage::CommandInvocation { command, call_id, .. } => {
+                    let dispatched = self.take_dispatched(command, call_id);
+                    invocations.push((start + i, command, call_id, dispatched));
                 }
                 _ => {
                     eprintln!("Unexpected response message: {}", response);
@@ -334,6 +793,60 @@ impl Sourcemapt {
             }
         }
 
+        // Commands in one response can't see each other's output, so they run concurrently
+        let sourcemapt = &*self;
+        let results = stream::iter(invocations.iter_mut())
+            .map(|(position, command, call_id, dispatched)| async move {
+                match dispatched.take() {
+                    Some(result) => result,
+                    None => sourcemapt.execute_command(command, (*call_id).clone(), *position).await,
+                }
+            })
+            .buffered(self.budget.max_concurrent_commands.max(1))
+            .collect::<Vec<_>>()
+            .await;
+
+        let mut command_results = Vec::new();
+        // Why the session has to end after this turn. The remaining results are still recorded,
+        // as a tool call left without one makes every later request fail
+        let mut stop = None;
+
+        for ((position, command, call_id, _), result) in invocations.into_iter().zip(results) {
+            println!("");
+
+            let result = match result {
+                Ok(result) => {
+                    self.consecutive_errors = 0;
+                    result
+                }
+                Err(e) => {
+                    print_error!("Command failed: {}: {}", command.serialize(), e);
+                    let result = command_error(command, &e, call_id.clone());
+
+                    self.consecutive_errors += 1;
+                    // The model can't fix credentials or rate limits, so those end the session
+                    if stop.is_none() && e.is_fatal() {
+                        stop = Some(e);
+                    } else if stop.is_none() && self.consecutive_errors > self.budget.max_consecutive_errors {
+                        print_error!("Gave up after {} failed commands in a row", self.consecutive_errors);
+                        stop = Some(e);
+                    }
+
+                    result
+                }
+            };
+
+            if let Command::Decide { .. } = command {
+                self.close_scope(position);
+            }
+            command_results.push(result);
+        }
+
+        if let Some(e) = stop {
+            self.messages.extend(command_results);
+            return Err(e);
+        }
+
         if !(command_results.is_empty()) {
             return Ok(ProcessResponsesOutcome::CallWithCommandResults(command_results));
         }
@@ -349,6 +862,311 @@ impl Sourcemapt {
         Ok(ProcessResponsesOutcome::CallForIntrospect)
     }
 
+    /// Runs `command`, whose invocation is at `position` in the history. A `!DECIDE` closes the
+    /// scope it's in there, whatever the rest of its response does.
+    async fn execute_command(
+        &self,
+        command: &Command,
+        call_id: Option<String>,
+        position: usize,
+    ) -> Result<SourcemaptMessage, SourcemaptError> {
+        match command {
+            Command::Scope { name } => {
+                Ok(SourcemaptMessage::CommandResult {
+                    content: format!("Started scope `{}`", name),
+                    hidden: false,
+                    call_id,
+                })
+            }
+            Command::Decide { rationale } => {
+                let (_, scope) = self.current_scope(position);
+
+                Ok(SourcemaptMessage::Decision {
+                    scope,
+                    rationale: rationale.clone(),
+                    hidden: false,
+                    call_id,
+                })
+            }
+            Command::SearchFiles { keywords } => {
+                let res = self.code_host.search_files(
+                    &self.repo,
+                    self.rev(),
+                    keywords.as_slice(),
+                ).await?;
+
+                let json = serde_json::to_string(&res)?;
+
+                Ok(SourcemaptMessage::CommandResult {
+                    content: json,
+                    hidden: false,
+                    call_id,
+                })
+            }
+            Command::ReadLines { file, start, n } => {
+                let content = self.code_host.get_file_content(
+                    &self.repo,
+                    self.rev(),
+                    file,
+                ).await?.content;
+
+                let line_count = content.lines().count();
+                if *start >= line_count {
+                    return Err(SourcemaptError::InvalidCommand(format!(
+                        "line {} is past the end of {}, which has {} lines",
+                        start, file, line_count
+                    )));
+                }
+
+                let lines = content.lines()
+                    .skip(*start)
+                    .take(*n)
+                    .map(|v| v.to_owned())
+                    .collect::<Vec<String>>();
+
+                let mut code = CodeBlock {
+                    path: file.clone(),
+                    lines: lines,
+                    start: *start,
+                    repo: None,
+                    hover: None,
+                    elisions: Vec::new(),
+                };
+                self.filters.apply(&mut code);
+
+                Ok(SourcemaptMessage::Code {
+                    code,
+                    hidden: false,
+                    call_id,
+                })
+            }
+            Command::Jump { file, line, char, n } => {
+                let v = self.code_host.get_definition(
+                    &self.repo,
+                    self.rev(),
+                    file,
+                    *line as u32,
+                    *char as u32,
+                ).await?;
+
+                if v.definitions.is_empty() {
+                    return Ok(SourcemaptMessage::CommandResult {
+                        content: format!("Couldn't find definition for `{}`", file),
+                        hidden: false,
+                        call_id,
+                    });
+                }
+                let hover = v.hover.map(|v| v.markdown);
+
+                // A definition that can't be read, such as one in a repository the host can't
+                // fetch, is still listed
+                let mut blocks = future::join_all(
+                    v.definitions
+                        .iter()
+                        .take(MAX_JUMP_DEFINITIONS)
+                        .map(|def| self.read_definition(def, *n)),
+                ).await;
+
+                if let [Ok(_)] = blocks.as_slice() {
+                    let mut code = blocks.remove(0)?;
+                    code.hover = hover;
+
+                    return Ok(SourcemaptMessage::Code {
+                        code,
+                        hidden: false,
+                        call_id,
+                    });
+                }
+
+                Ok(SourcemaptMessage::CommandResult {
+                    content: format_definitions(&self.repo, &v.definitions, hover, &blocks),
+                    hidden: false,
+                    call_id,
+                })
+            }
+            Command::ListDir { path, depth } => {
+                let res = self.code_host.list_dir(
+                    &self.repo,
+                    self.rev(),
+                    path,
+                    (*depth).max(1),
+                ).await?;
+
+                let json = serde_json::to_string(&res)?;
+
+                Ok(SourcemaptMessage::CommandResult {
+                    content: json,
+                    hidden: false,
+                    call_id,
+                })
+            }
+            Command::Symbols { query, kind } => {
+                let res = self.code_host.get_symbols(
+                    &self.repo,
+                    self.rev(),
+                    query,
+                    kind.as_deref(),
+                ).await?;
+
+                let json = serde_json::to_string(&res)?;
+
+                Ok(SourcemaptMessage::CommandResult {
+                    content: json,
+                    hidden: false,
+                    call_id,
+                })
+            }
+            Command::Hover { file, line, char } => {
+                let definition_result = self.code_host.get_definition(
+                    &self.repo,
+                    self.rev(),
+                    file,
+                    *line as u32,
+                    *char as u32,
+                ).await?;
+
+                let content = match definition_result.hover {
+                    Some(hover) => hover.markdown,
+                    None => format!("Couldn't find hover information for `{}`", file),
+                };
+
+                Ok(SourcemaptMessage::CommandResult {
+                    content,
+                    hidden: false,
+                    call_id,
+                })
+            }
+            Command::References { file, line, char, after } => {
+                let res = self.code_host.get_references(
+                    &self.repo,
+                    self.rev(),
+                    file,
+                    *line as u32,
+                    *char as u32,
+                    after.as_deref(),
+                ).await?;
+
+                let json = serde_json::to_string(&res)?;
+
+                Ok(SourcemaptMessage::CommandResult {
+                    content: json,
+                    hidden: false,
+                    call_id,
+                })
+            }
+            Command::Implementations { file, line, char, after } => {
+                let res = self.code_host.get_implementations(
+                    &self.repo,
+                    self.rev(),
+                    file,
+                    *line as u32,
+                    *char as u32,
+                    after.as_deref(),
+                ).await?;
+
+                let json = serde_json::to_string(&res)?;
+
+                Ok(SourcemaptMessage::CommandResult {
+                    content: json,
+                    hidden: false,
+                    call_id,
+                })
+            }
+            Command::Invalid { error, .. } => Err(SourcemaptError::InvalidCommand(error.clone())),
+        }
+    }
+
+    /// The first `n` lines of a definition found by `!JUMP`, which may be in another repository.
+    async fn read_definition(
+        &self,
+        def: &DefinitionNode,
+        n: usize,
+    ) -> Result<CodeBlock, SourcemaptError> {
+        let content = self.code_host.get_file_content(
+            &def.resource.repo,
+            &def.resource.commit_oid,
+            &def.resource.path,
+        ).await?.content;
+
+        let lines = content.lines()
+            .skip(def.range.line_start as usize)
+            .take(n)
+            .map(|v| v.to_owned())
+            .collect::<Vec<String>>();
+
+        let mut code = CodeBlock {
+            path: def.resource.path.clone(),
+            lines: lines,
+            start: def.range.line_start as usize,
+            repo: Some(def.resource.repo.clone()).filter(|v| v != &self.repo),
+            hover: None,
+            elisions: Vec::new(),
+        };
+        self.filters.apply(&mut code);
+
+        Ok(code)
+    }
+
+    /// Where the scope that is open at `end` in the history starts, and its name. A scope is
+    /// opened by `!SCOPE`, and implicitly by a question or the previous decision.
+    fn current_scope(&self, end: usize) -> (usize, String) {
+        for (i, message) in self.messages[..end].iter().enumerate().rev() {
+            match message {
+                SourcemaptMessage::CommandInvocation { command: Command::Scope { name }, .. } => {
+                    return (i + 1, name.clone());
+                }
+                SourcemaptMessage::User { .. } | SourcemaptMessage::Decision { .. } => {
+                    return (i + 1, DEFAULT_SCOPE.to_owned());
+                }
+                _ => {}
+            }
+        }
+
+        (0, DEFAULT_SCOPE.to_owned())
+    }
+
+    /// Hides the command output gathered in the scope that the `!DECIDE` at `end` closes; the
+    /// `Decision` returned for it stands in for the output. Output of commands run in the same turn
+    /// as the `!DECIDE` isn't in the history yet, so it stays.
+    fn close_scope(&mut self, end: usize) {
+        let (start, name) = self.current_scope(end);
+
+        // The `!SCOPE` that opened the scope stays visible, and so does its result, which may only
+        // join the history after the scope closes
+        let opener = match start.checked_sub(1).map(|i| &self.messages[i]) {
+            Some(SourcemaptMessage::CommandInvocation { command: Command::Scope { .. }, call_id, .. }) => {
+                call_id.clone()
+            }
+            _ => None,
+        };
+
+        let mut call_ids = Vec::new();
+        for message in &mut self.messages[start..end] {
+            if message.hidden() { continue; }
+            let call_id = match message {
+                SourcemaptMessage::Code { call_id, .. }
+                | SourcemaptMessage::CommandResult { call_id, .. }
+                | SourcemaptMessage::Elided { call_id, .. } => call_id.clone(),
+                _ => continue,
+            };
+            if call_id.is_some() && call_id == opener { continue; }
+
+            message.hide();
+            call_ids.extend(call_id);
+        }
+
+        // Tool calls can't be left without their results
+        for message in &mut self.messages[start..end] {
+            if let SourcemaptMessage::CommandInvocation { call_id: Some(id), .. } = message {
+                if call_ids.contains(id) {
+                    message.hide();
+                }
+            }
+        }
+
+        print_progress!("Closed scope `{}`", name);
+    }
+
     fn compact(&mut self) {
         let mut messages = self.messages.iter_mut().peekable();
 
@@ -366,62 +1184,98 @@ impl Sourcemapt {
             }
         }
 
-        for message in &self.messages {
-            match message {
-                SourcemaptMessage::System { .. } => {}
-                SourcemaptMessage::User { .. } => {}
-                SourcemaptMessage::Code { .. } => {}
-                SourcemaptMessage::Injected { .. } => {}
-                SourcemaptMessage::Model { .. } => {}
-                SourcemaptMessage::CommandInvocation { .. } => {}
-                SourcemaptMessage::CommandResult { .. } => {}
-            }
-        }
+        self.compact_to_budget();
     }
-}
 
-// TODO: If a Model response contains the same code sent as a User message (the model is attempting
-// to show the user what it found), cut that code from the response.
+    fn prompt_tokens(&self) -> usize {
+        self.messages
+            .iter()
+            .filter(|v| !v.hidden())
+            .map(|v| v.token_count())
+            .sum()
+    }
 
-fn read_or_create_config() -> Result<String, Box<dyn Error>> {
-    let config_dir = dirs::config_dir().ok_or("Unable to find config directory")?;
-    let config_path = config_dir.join("sourcemapt.toml");
+    /// Keeps the visible history under `budget.max_prompt_tokens`. Command output is swapped for
+    /// stubs first, oldest first; if that isn't enough, older model messages and command
+    /// invocations are hidden too. System and user messages and decisions are never touched, and
+    /// neither is the latest exchange.
+    fn compact_to_budget(&mut self) {
+        let budget = self.budget.max_prompt_tokens;
+        let mut total = self.prompt_tokens();
+        if total <= budget {
+            return;
+        }
 
-    if !config_dir.exists() {
-        fs::create_dir_all(&config_dir)?;
-    }
+        let mut protected_from = self.messages.len().saturating_sub(KEEP_RECENT_MESSAGES);
+
+        let mut i = 0;
+        while total > budget && i < protected_from {
+            if !self.messages[i].hidden() {
+                if let Some(stub) = self.messages[i].elide() {
+                    print_progress!("Eliding message: {}", stub);
+                    total = total - self.messages[i].token_count() + stub.token_count();
+                    self.messages[i].hide();
+                    self.messages.insert(i + 1, stub);
+                    protected_from += 1;
+                    i += 1;
+                }
+            }
+            i += 1;
+        }
 
-    if !config_path.exists() {
-        let mut file = File::create(&config_path)?;
-        file.write_all(br#"key = """#)?;
-        print_success!(
-            "Created a new configuration file at: {}",
-            config_path.display()
-        );
-        print_success!("Set the 'key' value in the file before using the program.");
-        process::exit(1);
-    }
+        for i in 0..protected_from {
+            if total <= budget {
+                break;
+            }
 
-    let config = fs::read_to_string(&config_path)?.parse::<Value>()?;
+            let message = &self.messages[i];
+            if message.hidden() {
+                continue;
+            }
+            if !matches!(
+                message,
+                SourcemaptMessage::Model { .. }
+                    | SourcemaptMessage::Injected { .. }
+                    | SourcemaptMessage::CommandInvocation { .. }
+                    | SourcemaptMessage::Elided { .. }
+            ) {
+                continue;
+            }
+            // Hiding a `!DECIDE` tool call would take its decision with it
+            if let SourcemaptMessage::CommandInvocation { command: Command::Decide { .. }, .. } = message {
+                continue;
+            }
 
-    let key = match config.get("key") {
-        Some(key) => key.as_str().unwrap_or("").to_owned(),
-        None => {
-            print_error!(
-                "The 'key' value is not set in the configuration file: {}",
-                config_path.display()
-            );
-            process::exit(1);
+            print_progress!("Hiding message to stay under the token budget: {}", message);
+            total -= message.token_count();
+
+            // A tool call and its result have to be hidden together
+            let call_id = match message {
+                SourcemaptMessage::CommandInvocation { call_id, .. } => call_id.clone(),
+                SourcemaptMessage::Elided { call_id, .. } => call_id.clone(),
+                _ => None,
+            };
+            self.messages[i].hide();
+
+            if let Some(call_id) = call_id {
+                for other in self.messages.iter_mut() {
+                    if other.hidden() { continue; }
+                    let matches = match other {
+                        SourcemaptMessage::CommandInvocation { call_id: id, .. } => id.as_ref() == Some(&call_id),
+                        SourcemaptMessage::Elided { call_id: id, .. } => id.as_ref() == Some(&call_id),
+                        SourcemaptMessage::Decision { call_id: id, .. } => id.as_ref() == Some(&call_id),
+                        _ => false,
+                    };
+                    if matches {
+                        total -= other.token_count();
+                        other.hide();
+                    }
+                }
+            }
         }
-    };
 
-    if key.is_empty() {
-        print_error!(
-            "Set the 'key' value in the configuration file before using the program: {}",
-            config_path.display()
-        );
-        process::exit(1);
+        if total > budget {
+            print_error!("History is still ~{} tokens, over the budget of {}", total, budget);
+        }
     }
-
-    Ok(key)
 }
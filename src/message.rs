@@ -1,15 +1,22 @@
 use std::fmt;
-use openai_dive::v1::resources::chat_completion::{ChatMessage, Role};
+use crate::llm::{ChatMessage, ChatRole, ToolCall};
 use crate::types::{CodeBlock, Command, InjectedMessage};
+use serde::{Deserialize, Serialize};
 
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "type")]
 pub enum SourcemaptMessage {
     System { content: String, hidden: bool },
     User { content: String, hidden: bool },
-    Code { code: CodeBlock, hidden: bool },
+    Code { code: CodeBlock, hidden: bool, call_id: Option<String> },
     Injected { kind: InjectedMessage, hidden: bool },
     Model { content: String, hidden: bool },
-    CommandInvocation { command: Command, hidden: bool },
-    CommandResult { content: String, hidden: bool },
+    CommandInvocation { command: Command, hidden: bool, call_id: Option<String> },
+    CommandResult { content: String, hidden: bool, call_id: Option<String> },
+    /// Short stand-in for a `Code` or `CommandResult` message hidden to save tokens.
+    Elided { content: String, hidden: bool, call_id: Option<String> },
+    /// One-line record of a `!DECIDE`, standing in for the command output of the scope it closed.
+    Decision { scope: String, rationale: String, hidden: bool, call_id: Option<String> },
 }
 
 impl SourcemaptMessage {
@@ -22,6 +29,8 @@ impl SourcemaptMessage {
             SourcemaptMessage::Model { hidden, .. } => *hidden,
             SourcemaptMessage::CommandInvocation { hidden, .. } => *hidden,
             SourcemaptMessage::CommandResult { hidden, .. } => *hidden,
+            SourcemaptMessage::Elided { hidden, .. } => *hidden,
+            SourcemaptMessage::Decision { hidden, .. } => *hidden,
         }
     }
 
@@ -34,6 +43,8 @@ impl SourcemaptMessage {
             SourcemaptMessage::Model { content, .. } => return content.contains("IN SUMMARY:"),
             SourcemaptMessage::CommandInvocation { .. } => {}
             SourcemaptMessage::CommandResult { .. } => {}
+            SourcemaptMessage::Elided { .. } => {}
+            SourcemaptMessage::Decision { .. } => {}
         }
         false
     }
@@ -47,62 +58,124 @@ impl SourcemaptMessage {
             SourcemaptMessage::Model { hidden, .. } => *hidden = true,
             SourcemaptMessage::CommandInvocation { hidden, .. } => *hidden = true,
             SourcemaptMessage::CommandResult { hidden, .. } => *hidden = true,
+            SourcemaptMessage::Elided { hidden, .. } => *hidden = true,
+            SourcemaptMessage::Decision { hidden, .. } => *hidden = true,
         };
     }
 
+    /// Rough number of prompt tokens the message costs: about four characters per token, plus
+    /// a few for the message framing.
+    pub fn token_count(&self) -> usize {
+        let chat_message = self.map_to_chat_message();
+
+        let characters = chat_message.content.len()
+            + chat_message.tool_calls
+            .iter()
+            .map(|v| v.name.len() + v.arguments.len())
+            .sum::<usize>();
+
+        characters.div_ceil(4) + 4
+    }
+
+    /// A stub to show in place of this message once it's hidden, for messages holding command
+    /// output.
+    pub fn elide(&self) -> Option<SourcemaptMessage> {
+        let (content, call_id) = match self {
+            SourcemaptMessage::Code { code, call_id, .. } => (
+                format!("[hidden: {} lines of {}]", code.lines.len(), code.path),
+                call_id,
+            ),
+            SourcemaptMessage::CommandResult { content, call_id, .. } => (
+                format!("[hidden: {} lines of command output]", content.lines().count()),
+                call_id,
+            ),
+            _ => return None,
+        };
+
+        Some(SourcemaptMessage::Elided {
+            content,
+            hidden: false,
+            call_id: call_id.clone(),
+        })
+    }
+
     pub fn map_to_chat_message(&self) -> ChatMessage {
         match self {
-            SourcemaptMessage::System { content, .. } => ChatMessage {
-                role: Role::System,
-                content: content.clone(),
-                name: None,
-            },
-            SourcemaptMessage::User { content, .. } => ChatMessage {
-                role: Role::User,
-                content: content.clone(),
-                name: None,
-            },
-            SourcemaptMessage::Code { code, .. } => ChatMessage {
-                role: Role::User,
-                content: code.format(),
-                name: None,
-            },
-            SourcemaptMessage::Injected { kind, .. } => ChatMessage {
-                role: Role::User,
-                content: kind.get_string(),
-                name: None,
-            },
-            SourcemaptMessage::Model { content, .. } => ChatMessage {
-                role: Role::Assistant,
-                content: content.clone(),
-                name: None,
-            },
-            SourcemaptMessage::CommandInvocation { command, .. } => ChatMessage {
-                role: Role::Assistant,
-                content: command.serialize(),
-                name: None,
-            },
-            SourcemaptMessage::CommandResult { content, .. } => ChatMessage {
-                role: Role::User,
-                content: content.clone(),
-                name: None,
+            SourcemaptMessage::System { content, .. } => {
+                ChatMessage::new(ChatRole::System, content.clone())
+            }
+            SourcemaptMessage::User { content, .. } => {
+                ChatMessage::new(ChatRole::User, content.clone())
+            }
+            SourcemaptMessage::Code { code, call_id, .. } => {
+                result_message(code.format(), call_id)
+            }
+            SourcemaptMessage::Injected { kind, .. } => {
+                ChatMessage::new(ChatRole::User, kind.get_string())
+            }
+            SourcemaptMessage::Model { content, .. } => {
+                ChatMessage::new(ChatRole::Assistant, content.clone())
+            }
+            SourcemaptMessage::CommandInvocation { command, call_id, .. } => match call_id {
+                None => ChatMessage::new(ChatRole::Assistant, command.serialize()),
+                Some(call_id) => ChatMessage {
+                    role: ChatRole::Assistant,
+                    content: String::new(),
+                    tool_calls: vec![ToolCall {
+                        id: call_id.clone(),
+                        name: command.tool_name().to_owned(),
+                        arguments: command.tool_arguments(),
+                    }],
+                    tool_call_id: None,
+                },
             },
+            SourcemaptMessage::CommandResult { content, call_id, .. } => {
+                result_message(content.clone(), call_id)
+            }
+            SourcemaptMessage::Elided { content, call_id, .. } => {
+                result_message(content.clone(), call_id)
+            }
+            SourcemaptMessage::Decision { scope, rationale, call_id, .. } => {
+                result_message(decision_record(scope, rationale), call_id)
+            }
         }
     }
 }
 
+/// Command output goes back as a tool message if the command came from a tool call, and as a
+/// user message otherwise.
+fn result_message(content: String, call_id: &Option<String>) -> ChatMessage {
+    match call_id {
+        None => ChatMessage::new(ChatRole::User, content),
+        Some(call_id) => ChatMessage {
+            role: ChatRole::Tool,
+            content,
+            tool_calls: Vec::new(),
+            tool_call_id: Some(call_id.clone()),
+        },
+    }
+}
+
+fn decision_record(scope: &str, rationale: &str) -> String {
+    format!("[decided after exploring {}: {}]", scope, rationale)
+}
+
 impl fmt::Display for SourcemaptMessage {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         let (label, content, hidden) = match self {
             SourcemaptMessage::System { content, hidden } => ("System", content.clone(), hidden),
             SourcemaptMessage::User { content, hidden } => ("User", content.clone(), hidden),
-            SourcemaptMessage::Code { code, hidden } => ("Code", code.format(), hidden),
+            SourcemaptMessage::Code { code, hidden, .. } => ("Code", code.format(), hidden),
             SourcemaptMessage::Injected { kind, hidden } => ("UserInjected", kind.get_string(), hidden),
             SourcemaptMessage::Model { content, hidden } => ("ModelMessage", content.clone(), hidden),
-            SourcemaptMessage::CommandInvocation { command, hidden } => {
+            SourcemaptMessage::CommandInvocation { command, hidden, .. } => {
                 ("CommandInvocation", format!("{}", command), hidden)
             }
-            SourcemaptMessage::CommandResult { content, hidden } => ("CommandResult", content.clone(), hidden),
+            SourcemaptMessage::CommandResult { content, hidden, .. } => ("CommandResult", content.clone(), hidden),
+            SourcemaptMessage::Elided { content, hidden, .. } => ("Elided", content.clone(), hidden),
+            SourcemaptMessage::Decision { scope, rationale, hidden, .. } => {
+                ("Decision", decision_record(scope, rationale), hidden)
+            }
         };
 
         write!(f, "{} [hidden: {}]\n", label, hidden)?;
@@ -128,9 +201,10 @@ impl Clone for SourcemaptMessage {
                 content: content.clone(),
                 hidden: *hidden,
             },
-            SourcemaptMessage::Code { code, hidden } => SourcemaptMessage::Code {
+            SourcemaptMessage::Code { code, hidden, call_id } => SourcemaptMessage::Code {
                 code: code.clone(),
                 hidden: *hidden,
+                call_id: call_id.clone(),
             },
             SourcemaptMessage::Injected { kind, hidden } => SourcemaptMessage::Injected {
                 kind: kind.clone(),
@@ -140,16 +214,81 @@ impl Clone for SourcemaptMessage {
                 content: content.clone(),
                 hidden: *hidden,
             },
-            SourcemaptMessage::CommandInvocation { command, hidden } =>
+            SourcemaptMessage::CommandInvocation { command, hidden, call_id } =>
                 SourcemaptMessage::CommandInvocation {
                     command: command.clone(),
                     hidden: *hidden,
+                    call_id: call_id.clone(),
                 },
-            SourcemaptMessage::CommandResult { content, hidden } =>
+            SourcemaptMessage::CommandResult { content, hidden, call_id } =>
                 SourcemaptMessage::CommandResult {
                     content: content.clone(),
                     hidden: *hidden,
+                    call_id: call_id.clone(),
+                },
+            SourcemaptMessage::Elided { content, hidden, call_id } =>
+                SourcemaptMessage::Elided {
+                    content: content.clone(),
+                    hidden: *hidden,
+                    call_id: call_id.clone(),
+                },
+            SourcemaptMessage::Decision { scope, rationale, hidden, call_id } =>
+                SourcemaptMessage::Decision {
+                    scope: scope.clone(),
+                    rationale: rationale.clone(),
+                    hidden: *hidden,
+                    call_id: call_id.clone(),
                 },
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::tests::every_command;
+
+    fn every_message() -> Vec<SourcemaptMessage> {
+        let call_id = || Some("call_1".to_owned());
+        let code = CodeBlock {
+            path: "pkg/reconciler.go".to_owned(),
+            lines: vec!["func (rc *reconciler) StatesHasBeenSynced() bool {".to_owned()],
+            start: 119,
+            repo: Some("github.com/kubernetes/utils".to_owned()),
+            hover: Some("func StatesHasBeenSynced() bool".to_owned()),
+            elisions: Vec::new(),
+        };
+
+        let mut messages = vec![
+            SourcemaptMessage::System { content: "You explore code".to_owned(), hidden: false },
+            SourcemaptMessage::User { content: "What does StatesHasBeenSynced do?".to_owned(), hidden: false },
+            SourcemaptMessage::Code { code, hidden: true, call_id: call_id() },
+            SourcemaptMessage::Injected { kind: InjectedMessage::AskToSummarize, hidden: false },
+            SourcemaptMessage::Model { content: "IN SUMMARY: it reports sync state".to_owned(), hidden: false },
+            SourcemaptMessage::CommandResult { content: "{}".to_owned(), hidden: false, call_id: None },
+            SourcemaptMessage::Elided { content: "[hidden: 1 lines]".to_owned(), hidden: false, call_id: call_id() },
+            SourcemaptMessage::Decision {
+                scope: "reconstruction".to_owned(),
+                rationale: "The flag is set by the reconciler".to_owned(),
+                hidden: false,
+                call_id: call_id(),
+            },
+        ];
+        messages.extend(every_command().into_iter().map(|command| SourcemaptMessage::CommandInvocation {
+            command,
+            hidden: false,
+            call_id: call_id(),
+        }));
+
+        messages
+    }
+
+    #[test]
+    fn message_round_trips_through_json() {
+        for message in every_message() {
+            let json = serde_json::to_string(&message).unwrap();
+            let parsed = serde_json::from_str::<SourcemaptMessage>(&json).unwrap();
+            assert_eq!(serde_json::to_string(&parsed).unwrap(), json);
+        }
+    }
+}
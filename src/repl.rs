@@ -0,0 +1,121 @@
+use crate::error::SourcemaptError;
+use crate::Sourcemapt;
+use crossterm::queue;
+use std::io::{self, BufRead, Write};
+
+const HELP: &str = r#"
+Type a follow-up question, or one of:
+  /reset               Start a new session, forgetting all previous messages
+  /history             Show every message in the current session
+  /repo <repo> [rev]   Switch to another repository (and optionally revision)
+  /help                Show this help
+  /quit                Exit
+"#;
+
+pub enum ReplCommand {
+    Ask(String),
+    Reset,
+    History,
+    Repo { repo: String, rev: Option<String> },
+    Help,
+    Quit,
+    Empty,
+    Unknown(String),
+}
+
+impl ReplCommand {
+    pub fn parse(line: &str) -> ReplCommand {
+        let line = line.trim();
+
+        if line.is_empty() {
+            return ReplCommand::Empty;
+        }
+
+        if !line.starts_with('/') {
+            return ReplCommand::Ask(line.to_owned());
+        }
+
+        let mut parts = line.split_whitespace();
+        let name = parts.next().unwrap_or("");
+
+        match name {
+            "/reset" => ReplCommand::Reset,
+            "/history" => ReplCommand::History,
+            "/repo" => match parts.next() {
+                Some(repo) => ReplCommand::Repo {
+                    repo: repo.to_owned(),
+                    rev: parts.next().map(|v| v.to_owned()),
+                },
+                None => ReplCommand::Unknown("Usage: /repo <repo> [rev]".to_owned()),
+            },
+            "/help" => ReplCommand::Help,
+            "/quit" | "/exit" => ReplCommand::Quit,
+            _ => ReplCommand::Unknown(format!("Unknown command: {}", name)),
+        }
+    }
+}
+
+/// Reads follow-up questions from stdin and feeds them into the same session, so each question
+/// can build on the context gathered for the previous ones.
+pub async fn run(sourcemapt: &mut Sourcemapt) {
+    print_progress!("{}", HELP.trim());
+
+    loop {
+        eprint!("{}@{}> ", sourcemapt.repo, sourcemapt.refspec);
+        let _ = io::stderr().flush();
+
+        let line = match read_line().await {
+            Ok(Some(line)) => line,
+            Ok(None) => return,
+            Err(e) => {
+                print_error!("Failed to read input: {}", e);
+                return;
+            }
+        };
+
+        match ReplCommand::parse(&line) {
+            ReplCommand::Ask(question) => {
+                if let Some(e) = sourcemapt.run_loop(question).await {
+                    print_error!("Error: {}", e);
+                }
+            }
+            ReplCommand::Reset => {
+                sourcemapt.reset();
+                print_success!("Session reset");
+            }
+            ReplCommand::History => {
+                for message in &sourcemapt.messages {
+                    println!("{}", message);
+                }
+            }
+            ReplCommand::Repo { repo, rev } => {
+                sourcemapt.repo = repo;
+                if let Some(rev) = rev {
+                    sourcemapt.refspec = rev;
+                }
+                if let Err(e) = sourcemapt.pin_revision().await {
+                    print_error!("Failed to resolve {}@{}: {}", sourcemapt.repo, sourcemapt.refspec, e);
+                    sourcemapt.commit_oid = None;
+                }
+                print_success!("Now exploring {}@{}", sourcemapt.repo, sourcemapt.refspec);
+            }
+            ReplCommand::Help => print_progress!("{}", HELP.trim()),
+            ReplCommand::Quit => return,
+            ReplCommand::Empty => {}
+            ReplCommand::Unknown(message) => print_error!("{}", message),
+        }
+    }
+}
+
+/// The next line of stdin, or `None` at the end of input. It's read on a blocking thread, so the
+/// runtime isn't stalled while waiting for the user.
+async fn read_line() -> Result<Option<String>, SourcemaptError> {
+    tokio::task::spawn_blocking(|| -> Result<_, SourcemaptError> {
+        let mut line = String::new();
+        match io::stdin().lock().read_line(&mut line)? {
+            0 => Ok(None),
+            _ => Ok(Some(line)),
+        }
+    })
+        .await?
+}
@@ -0,0 +1,109 @@
+use crate::error::SourcemaptError;
+use crate::message::SourcemaptMessage;
+use crossterm::queue;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+#[derive(Serialize, Deserialize)]
+pub struct Session {
+    pub id: String,
+    /// Seconds since the Unix epoch.
+    pub created: u64,
+    pub repo: String,
+    pub refspec: String,
+    /// The commit `refspec` was pinned to when the session started.
+    #[serde(default)]
+    pub commit_oid: Option<String>,
+    pub messages: Vec<SourcemaptMessage>,
+}
+
+impl Session {
+    pub fn new_id() -> String {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        format!("{:x}", now.as_millis())
+    }
+
+    pub fn now() -> u64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs()
+    }
+
+    fn dir() -> Result<PathBuf, SourcemaptError> {
+        let data_dir = dirs::data_dir()
+            .ok_or_else(|| SourcemaptError::Io("Unable to find data directory".to_owned()))?;
+        Ok(data_dir.join("sourcemapt").join("sessions"))
+    }
+
+    fn path(id: &str) -> Result<PathBuf, SourcemaptError> {
+        Ok(Self::dir()?.join(format!("{}.json", id)))
+    }
+
+    pub fn save(&self) -> Result<(), SourcemaptError> {
+        fs::create_dir_all(Self::dir()?)?;
+
+        // Write to a temporary file first so an interrupted save can't corrupt the session
+        let path = Self::path(&self.id)?;
+        let tmp_path = path.with_extension("json.tmp");
+        fs::write(&tmp_path, serde_json::to_vec_pretty(self)?)?;
+        fs::rename(&tmp_path, &path)?;
+
+        Ok(())
+    }
+
+    pub fn load(id: &str) -> Result<Session, SourcemaptError> {
+        let path = Self::path(id)?;
+        let content = fs::read(&path).map_err(|e| match e.kind() {
+            io::ErrorKind::NotFound => SourcemaptError::NotFound(format!("session {}", id)),
+            _ => SourcemaptError::Io(format!("failed to read session {}: {}", path.display(), e)),
+        })?;
+        Ok(serde_json::from_slice(&content)?)
+    }
+
+    /// Every saved session, oldest first. Files that can't be parsed are skipped.
+    pub fn list() -> Result<Vec<Session>, SourcemaptError> {
+        let dir = Self::dir()?;
+        if !dir.exists() {
+            return Ok(Vec::new());
+        }
+
+        let mut sessions = Vec::new();
+
+        for entry in fs::read_dir(dir)? {
+            let path = entry?.path();
+            if path.extension().map_or(true, |v| v != "json") {
+                continue;
+            }
+
+            match fs::read(&path).map(|v| serde_json::from_slice::<Session>(&v)) {
+                Ok(Ok(session)) => sessions.push(session),
+                _ => print_error!("Skipping unreadable session: {}", path.display()),
+            }
+        }
+
+        sessions.sort_by_key(|v| v.created);
+        Ok(sessions)
+    }
+
+    /// The first question asked in the session.
+    pub fn question(&self) -> Option<&str> {
+        self.messages.iter().find_map(|v| match v {
+            SourcemaptMessage::User { content, .. } => Some(content.as_str()),
+            _ => None,
+        })
+    }
+
+    /// The most recent summary the model gave.
+    pub fn summary(&self) -> Option<&str> {
+        self.messages.iter().rev().find_map(|v| match v {
+            SourcemaptMessage::Model { content, .. } if v.is_summary() => Some(content.as_str()),
+            _ => None,
+        })
+    }
+}
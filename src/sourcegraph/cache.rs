@@ -0,0 +1,226 @@
+use crossterm::queue;
+use serde::Deserialize;
+use std::collections::{HashMap, VecDeque};
+use std::fs;
+use std::path::{Component, Path, PathBuf};
+use std::sync::Mutex;
+
+/// The `[sourcegraph.cache]` section of the configuration file.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct CacheConfig {
+    /// Files kept in memory; 0 disables the in-memory layer.
+    pub entries: usize,
+    /// Also keep files on disk, so later sessions on the same commit can reuse them.
+    pub disk: bool,
+    /// Where files are kept on disk. Defaults to `sourcemapt/files` in the user's cache directory.
+    pub dir: Option<PathBuf>,
+}
+
+impl Default for CacheConfig {
+    fn default() -> Self {
+        Self {
+            entries: 64,
+            disk: false,
+            dir: None,
+        }
+    }
+}
+
+/// Identifies a file's content. The commit has to be a resolved OID, not a branch name, for the
+/// content to never change.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct CacheKey {
+    pub repo: String,
+    pub commit_oid: String,
+    pub path: String,
+}
+
+/// File contents, in a least-recently-used in-memory layer backed by an optional on-disk layer.
+pub struct ContentCache {
+    capacity: usize,
+    memory: Mutex<Lru>,
+    dir: Option<PathBuf>,
+}
+
+#[derive(Default)]
+struct Lru {
+    entries: HashMap<CacheKey, String>,
+    /// Least recently used first.
+    order: VecDeque<CacheKey>,
+}
+
+impl ContentCache {
+    pub fn new(config: &CacheConfig) -> Self {
+        let dir = match config.disk {
+            true => config
+                .dir
+                .clone()
+                .or_else(|| dirs::cache_dir().map(|v| v.join("sourcemapt").join("files"))),
+            false => None,
+        };
+
+        Self {
+            capacity: config.entries,
+            memory: Mutex::new(Lru::default()),
+            dir,
+        }
+    }
+
+    pub fn get(&self, key: &CacheKey) -> Option<String> {
+        if let Some(content) = self.memory.lock().unwrap().get(key) {
+            return Some(content);
+        }
+
+        let content = fs::read_to_string(self.disk_path(key)?).ok()?;
+        self.insert_memory(key.clone(), content.clone());
+        Some(content)
+    }
+
+    pub fn insert(&self, key: CacheKey, content: String) {
+        if let Some(path) = self.disk_path(&key) {
+            if let Err(e) = write_file(&path, &content) {
+                print_error!("Failed to cache {}: {}", path.display(), e);
+            }
+        }
+
+        self.insert_memory(key, content);
+    }
+
+    fn insert_memory(&self, key: CacheKey, content: String) {
+        if self.capacity == 0 {
+            return;
+        }
+
+        let mut memory = self.memory.lock().unwrap();
+        memory.insert(key, content);
+        while memory.order.len() > self.capacity {
+            memory.evict();
+        }
+    }
+
+    /// `<dir>/<repo>/<commit>/<path>`. Keys that could escape the directory aren't stored on disk.
+    fn disk_path(&self, key: &CacheKey) -> Option<PathBuf> {
+        let dir = self.dir.as_ref()?;
+
+        let relative = Path::new(&key.repo).join(&key.commit_oid).join(&key.path);
+        if !relative.components().all(|v| matches!(v, Component::Normal(_))) {
+            return None;
+        }
+
+        Some(dir.join(relative))
+    }
+}
+
+impl Lru {
+    fn get(&mut self, key: &CacheKey) -> Option<String> {
+        let content = self.entries.get(key)?.clone();
+        self.touch(key);
+        Some(content)
+    }
+
+    fn insert(&mut self, key: CacheKey, content: String) {
+        if self.entries.insert(key.clone(), content).is_some() {
+            self.touch(&key);
+        } else {
+            self.order.push_back(key);
+        }
+    }
+
+    fn touch(&mut self, key: &CacheKey) {
+        if let Some(i) = self.order.iter().position(|v| v == key) {
+            let key = self.order.remove(i).expect("position is in bounds");
+            self.order.push_back(key);
+        }
+    }
+
+    fn evict(&mut self) {
+        if let Some(key) = self.order.pop_front() {
+            self.entries.remove(&key);
+        }
+    }
+}
+
+/// Writes through a temporary file, so a reader never sees a partly written file.
+fn write_file(path: &Path, content: &str) -> std::io::Result<()> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+
+    let mut tmp_path = path.as_os_str().to_owned();
+    tmp_path.push(".tmp");
+    fs::write(&tmp_path, content)?;
+    fs::rename(&tmp_path, path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cache_key(repo: &str, commit_oid: &str, path: &str) -> CacheKey {
+        CacheKey {
+            repo: repo.to_owned(),
+            commit_oid: commit_oid.to_owned(),
+            path: path.to_owned(),
+        }
+    }
+
+    #[test]
+    fn content_cache_evicts_least_recently_used() {
+        let cache = ContentCache::new(&CacheConfig {
+            entries: 2,
+            ..CacheConfig::default()
+        });
+        let (a, b, c) = (
+            cache_key("github.com/example/repo", "abc", "a.go"),
+            cache_key("github.com/example/repo", "abc", "b.go"),
+            cache_key("github.com/example/repo", "abc", "c.go"),
+        );
+
+        cache.insert(a.clone(), "a".to_owned());
+        cache.insert(b.clone(), "b".to_owned());
+        // Reading a makes b the least recently used
+        assert_eq!(cache.get(&a).as_deref(), Some("a"));
+        cache.insert(c.clone(), "c".to_owned());
+
+        assert_eq!(cache.get(&a).as_deref(), Some("a"));
+        assert_eq!(cache.get(&b), None);
+        assert_eq!(cache.get(&c).as_deref(), Some("c"));
+    }
+
+    #[test]
+    fn content_cache_keys_by_repo_commit_and_path() {
+        let cache = ContentCache::new(&CacheConfig::default());
+        let key = cache_key("github.com/example/repo", "abc", "a.go");
+        cache.insert(key.clone(), "a".to_owned());
+
+        assert_eq!(cache.get(&key).as_deref(), Some("a"));
+        assert_eq!(cache.get(&cache_key("github.com/example/repo", "def", "a.go")), None);
+        assert_eq!(cache.get(&cache_key("github.com/example/fork", "abc", "a.go")), None);
+        assert_eq!(cache.get(&cache_key("github.com/example/repo", "abc", "b.go")), None);
+    }
+
+    #[test]
+    fn content_cache_round_trips_through_disk() {
+        let dir = std::env::temp_dir().join(format!("sourcemapt-cache-test-{}", std::process::id()));
+        // No in-memory layer, so every read has to come from disk
+        let config = CacheConfig {
+            entries: 0,
+            disk: true,
+            dir: Some(dir.clone()),
+        };
+        let key = cache_key("github.com/example/repo", "abc", "pkg/a.go");
+        let escaping = cache_key("github.com/example/repo", "abc", "../../a.go");
+
+        ContentCache::new(&config).insert(key.clone(), "package a\n".to_owned());
+        ContentCache::new(&config).insert(escaping.clone(), "package a\n".to_owned());
+
+        let cache = ContentCache::new(&config);
+        let content = cache.get(&key);
+        let escaped = cache.get(&escaping);
+        let _ = std::fs::remove_dir_all(&dir);
+
+        assert_eq!(content.as_deref(), Some("package a\n"));
+        assert_eq!(escaped, None);
+    }
+}
@@ -1,45 +1,155 @@
-use graphql_client::reqwest::post_graphql;
+use crate::config::ConfigError;
+use crate::error::SourcemaptError;
+use crate::sourcegraph::cache::{CacheConfig, ContentCache};
 use graphql_client::{GraphQLQuery, Response};
-use reqwest::{Client, Error};
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue};
+use reqwest::{Certificate, Client};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::fmt::Display;
+use std::fs;
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+const DEFAULT_ENDPOINT: &str = "https://sourcegraph.com";
+const GRAPHQL_PATH: &str = "/.api/graphql";
+
+/// The `[sourcegraph]` section of the configuration file.
+#[derive(Debug, Default, Clone, Deserialize)]
+pub struct SourcegraphConfig {
+    /// Base URL of the instance, e.g. `https://sourcegraph.example.com`. Defaults to
+    /// sourcegraph.com.
+    pub endpoint: Option<String>,
+    /// Access token. Falls back to the `SOURCEGRAPH_API_TOKEN` env var; without either, requests
+    /// are made anonymously.
+    pub token: Option<String>,
+    /// Extra headers sent with every request.
+    #[serde(default)]
+    pub headers: HashMap<String, String>,
+    /// PEM file with additional root certificates to trust.
+    pub ca_bundle: Option<PathBuf>,
+    #[serde(default)]
+    pub cache: CacheConfig,
+}
 
 pub struct SourcegraphClient {
     pub(crate) client: Client,
+    endpoint: String,
+    pub(crate) cache: ContentCache,
+    /// Commit OIDs of the revisions resolved so far, by repo and revision.
+    pub(crate) revisions: Mutex<HashMap<(String, String), String>>,
 }
 
 impl SourcegraphClient {
-    pub fn new() -> Self {
-        let sourcegraph_api_token =
-            std::env::var("SOURCEGRAPH_API_TOKEN").expect("Missing SOURCEGRAPH_API_TOKEN env var");
+    pub fn new(config: &SourcegraphConfig) -> Result<Self, ConfigError> {
+        let mut headers = HeaderMap::new();
+
+        let token = config
+            .token
+            .clone()
+            .or_else(|| std::env::var("SOURCEGRAPH_API_TOKEN").ok())
+            .filter(|v| !v.is_empty());
+        if let Some(token) = token {
+            headers.insert(
+                reqwest::header::AUTHORIZATION,
+                HeaderValue::from_str(&format!("Bearer {}", token)).map_err(invalid)?,
+            );
+        }
+
+        for (name, value) in &config.headers {
+            headers.insert(
+                HeaderName::from_bytes(name.as_bytes()).map_err(invalid)?,
+                HeaderValue::from_str(value).map_err(invalid)?,
+            );
+        }
 
-        let client = Client::builder()
+        let mut builder = Client::builder()
             .user_agent("graphql-rust/0.10.0")
-            .default_headers(
-                std::iter::once((
-                    reqwest::header::AUTHORIZATION,
-                    reqwest::header::HeaderValue::from_str(&format!(
-                        "Bearer {}",
-                        sourcegraph_api_token
-                    ))
-                        .unwrap(),
-                ))
-                    .collect(),
-            )
-            .build()
-            .expect("Failed to build reqwest::Client");
-
-        SourcegraphClient { client }
+            .default_headers(headers);
+
+        if let Some(ca_bundle) = &config.ca_bundle {
+            let pem = fs::read(ca_bundle).map_err(|e| ConfigError::Io(ca_bundle.clone(), e))?;
+            builder = builder.add_root_certificate(Certificate::from_pem(&pem).map_err(invalid)?);
+        }
+
+        let endpoint = config
+            .endpoint
+            .as_deref()
+            .unwrap_or(DEFAULT_ENDPOINT)
+            .trim_end_matches('/');
+        let endpoint = if endpoint.ends_with(GRAPHQL_PATH) {
+            endpoint.to_owned()
+        } else {
+            format!("{}{}", endpoint, GRAPHQL_PATH)
+        };
+
+        Ok(SourcegraphClient {
+            client: builder.build().map_err(invalid)?,
+            endpoint,
+            cache: ContentCache::new(&config.cache),
+            revisions: Mutex::new(HashMap::new()),
+        })
     }
 
+    /// Sends a query and returns its data, mapping HTTP statuses and GraphQL errors to the
+    /// matching `SourcemaptError`.
     pub async fn post<Q: GraphQLQuery>(
         &self,
         variables: Q::Variables,
-    ) -> Result<Response<<Q as GraphQLQuery>::ResponseData>, Error>
+    ) -> Result<<Q as GraphQLQuery>::ResponseData, SourcemaptError>
     {
-        post_graphql::<Q, _>(
-            &self.client,
-            "https://sourcegraph.com/.api/graphql",
-            variables,
-        )
-            .await
+        let body = Q::build_query(variables);
+        let operation = body.operation_name;
+
+        let response = self.client.post(&self.endpoint).json(&body).send().await?;
+
+        let status = response.status();
+        if !status.is_success() {
+            let text = response.text().await.unwrap_or_default();
+            return Err(SourcemaptError::from_status(
+                status,
+                format!("{} returned {}: {}", operation, status, text.trim()),
+            ));
+        }
+
+        let response = response
+            .json::<Response<<Q as GraphQLQuery>::ResponseData>>()
+            .await?;
+
+        match (response.data, response.errors) {
+            (Some(data), _) => Ok(data),
+            (None, Some(errors)) if !errors.is_empty() => {
+                let message = errors
+                    .iter()
+                    .map(|v| v.message.clone())
+                    .collect::<Vec<String>>()
+                    .join("; ");
+                Err(graphql_error(format!("{}: {}", operation, message)))
+            }
+            (None, _) => Err(SourcemaptError::Parse(format!("{} returned no data", operation))),
+        }
     }
 }
+
+/// GraphQL errors come with a 200 status, so their kind can only be told from the message. The
+/// request did reach the server, so anything unrecognised is a problem with the query or its
+/// arguments rather than the network.
+fn graphql_error(message: String) -> SourcemaptError {
+    let lowercase = message.to_lowercase();
+
+    if lowercase.contains("not found") || lowercase.contains("does not exist") {
+        SourcemaptError::NotFound(message)
+    } else if lowercase.contains("rate limit") {
+        SourcemaptError::RateLimited(message)
+    } else if lowercase.contains("unauthorized") || lowercase.contains("not authenticated") {
+        SourcemaptError::Auth(message)
+    } else if lowercase.contains("invalid") || lowercase.contains("argument") {
+        SourcemaptError::InvalidCommand(message)
+    } else {
+        SourcemaptError::Parse(message)
+    }
+}
+
+fn invalid(e: impl Display) -> ConfigError {
+    ConfigError::Invalid(format!("Failed to set up the Sourcegraph client: {}", e))
+}
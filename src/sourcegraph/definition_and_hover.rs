@@ -1,5 +1,5 @@
 use crate::sourcegraph::client::SourcegraphClient;
-use crate::sourcegraph::error::SourcegraphError;
+use crate::error::SourcemaptError;
 use graphql_client::GraphQLQuery;
 use serde::Deserialize;
 
@@ -13,10 +13,13 @@ struct LegacyDefinitionAndHover;
 
 type GitObjectID = String;
 
+/// What the code host knows about the symbol at a position. Either half can be missing without
+/// the other, e.g. a symbol from a dependency that has hover text but no indexed definition.
 #[derive(Debug, Deserialize)]
 pub struct GetDefinitionResult {
+    /// Empty when no definition could be resolved.
     pub definitions: Vec<DefinitionNode>,
-    pub hover: Hover,
+    pub hover: Option<Hover>,
 }
 
 #[derive(Debug, Deserialize)]
@@ -54,7 +57,7 @@ impl SourcegraphClient {
         path: &str,
         line: u32,
         char: u32,
-    ) -> Result<Option<GetDefinitionResult>, SourcegraphError> {
+    ) -> Result<GetDefinitionResult, SourcemaptError> {
         let variables = legacy_definition_and_hover::Variables {
             repository: repo.to_owned(),
             commit: rev.to_owned(),
@@ -65,39 +68,28 @@ impl SourcegraphClient {
 
         let response_body = self
             .post::<LegacyDefinitionAndHover>(variables.into())
-            .await
-            .map_err(|e| SourcegraphError(format!("failed to get definition: {}", e)))?
-            .data
-            .ok_or_else(|| SourcegraphError("missing data".to_owned()))?;
+            .await?;
 
         let repository = response_body
             .repository
-            .ok_or_else(|| SourcegraphError("missing repository".to_owned()))?;
+            .ok_or_else(|| SourcemaptError::NotFound(format!("repository {}", repo)))?;
 
         let commit = repository
             .commit
-            .ok_or_else(|| SourcegraphError("missing commit".to_owned()))?;
+            .ok_or_else(|| SourcemaptError::NotFound(format!("revision {} of {}", rev, repo)))?;
         let blob = commit
             .blob
-            .ok_or_else(|| SourcegraphError("missing blob".to_owned()))?;
+            .ok_or_else(|| SourcemaptError::NotFound(format!("file {}", path)))?;
         let lsif = blob
             .lsif
-            .ok_or_else(|| SourcegraphError("missing lsif".to_owned()))?;
-
-        if lsif.hover.is_none() || lsif.definitions.nodes.is_empty() {
-            return Ok(None);
-        }
+            .ok_or_else(|| SourcemaptError::NotFound(format!("code intelligence data for {}", path)))?;
 
         let definitions = lsif
             .definitions
             .nodes
             .iter()
             .filter_map(|v| {
-                let range = v
-                    .range
-                    .as_ref()
-                    .ok_or_else(|| SourcegraphError("missing range".to_string()))
-                    .ok()?;
+                let range = v.range.as_ref()?;
                 Some(DefinitionNode {
                     resource: Resource {
                         path: v.resource.path.clone(),
@@ -114,18 +106,20 @@ impl SourcegraphClient {
             })
             .collect();
 
-        let hover = lsif.hover.as_ref().unwrap();
-
-        let hover = Hover {
-            markdown: hover.markdown.text.to_owned(),
-            range: Range {
-                line_start: hover.range.start.line,
-                char_start: hover.range.start.character,
-                line_end: hover.range.end.line,
-                char_end: hover.range.end.character,
-            },
-        };
-
-        Ok(Some(GetDefinitionResult { definitions, hover }))
+        let hover = lsif
+            .hover
+            .as_ref()
+            .filter(|v| !v.markdown.text.trim().is_empty())
+            .map(|v| Hover {
+                markdown: v.markdown.text.to_owned(),
+                range: Range {
+                    line_start: v.range.start.line,
+                    char_start: v.range.start.character,
+                    line_end: v.range.end.line,
+                    char_end: v.range.end.character,
+                },
+            });
+
+        Ok(GetDefinitionResult { definitions, hover })
     }
 }
@@ -1,13 +0,0 @@
-use std::error::Error;
-use std::fmt::{Display, Formatter, Result as FmtResult};
-
-#[derive(Debug)]
-pub struct SourcegraphError(pub String);
-
-impl Display for SourcegraphError {
-    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
-        write!(f, "{}", self.0)
-    }
-}
-
-impl Error for SourcegraphError {}
@@ -0,0 +1,107 @@
+use serde_json::Value;
+use std::collections::HashMap;
+use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+/// A minimal HTTP server that answers GraphQL requests with canned `data` payloads, keyed by
+/// operation name, so `SourcegraphClient` can be exercised without touching the network.
+pub struct FakeSourcegraph {
+    endpoint: String,
+    operations: Arc<Mutex<Vec<String>>>,
+}
+
+impl FakeSourcegraph {
+    pub fn start(responses: HashMap<String, Value>) -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").expect("failed to bind fake sourcegraph");
+        let endpoint = format!("http://{}/.api/graphql", listener.local_addr().unwrap());
+        let operations = Arc::new(Mutex::new(Vec::new()));
+
+        let seen = operations.clone();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let stream = match stream {
+                    Ok(stream) => stream,
+                    Err(_) => continue,
+                };
+                handle(stream, &responses, &seen);
+            }
+        });
+
+        FakeSourcegraph { endpoint, operations }
+    }
+
+    pub fn endpoint(&self) -> String {
+        self.endpoint.clone()
+    }
+
+    /// The operation names of every request received so far, in order.
+    pub fn operations(&self) -> Vec<String> {
+        self.operations.lock().unwrap().clone()
+    }
+}
+
+fn handle(mut stream: TcpStream, responses: &HashMap<String, Value>, seen: &Mutex<Vec<String>>) {
+    let body = match read_body(&mut stream) {
+        Some(body) => body,
+        None => return,
+    };
+
+    let request: Value = serde_json::from_slice(&body).unwrap_or(Value::Null);
+    let operation = request
+        .get("operationName")
+        .and_then(|v| v.as_str())
+        .unwrap_or("")
+        .to_owned();
+    seen.lock().unwrap().push(operation.clone());
+
+    let payload = match responses.get(&operation) {
+        Some(data) => serde_json::json!({ "data": data }),
+        None => serde_json::json!({
+            "data": null,
+            "errors": [{ "message": format!("no canned response for {}", operation) }],
+        }),
+    }
+        .to_string();
+
+    let _ = write!(
+        stream,
+        "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        payload.len(),
+        payload
+    );
+}
+
+fn read_body(stream: &mut TcpStream) -> Option<Vec<u8>> {
+    let mut buffer = Vec::new();
+    let mut chunk = [0u8; 4096];
+
+    let header_end = loop {
+        let n = stream.read(&mut chunk).ok()?;
+        if n == 0 {
+            return None;
+        }
+        buffer.extend_from_slice(&chunk[..n]);
+        if let Some(pos) = buffer.windows(4).position(|w| w == b"\r\n\r\n") {
+            break pos + 4;
+        }
+    };
+
+    let headers = String::from_utf8_lossy(&buffer[..header_end]).to_lowercase();
+    let content_length = headers
+        .lines()
+        .find_map(|line| line.strip_prefix("content-length:"))
+        .and_then(|v| v.trim().parse::<usize>().ok())
+        .unwrap_or(0);
+
+    while buffer.len() < header_end + content_length {
+        let n = stream.read(&mut chunk).ok()?;
+        if n == 0 {
+            break;
+        }
+        buffer.extend_from_slice(&chunk[..n]);
+    }
+
+    Some(buffer[header_end..].to_vec())
+}
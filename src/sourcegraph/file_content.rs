@@ -1,6 +1,7 @@
+use crate::error::SourcemaptError;
+use crate::sourcegraph::cache::CacheKey;
 use crate::sourcegraph::client::SourcegraphClient;
 use graphql_client::GraphQLQuery;
-use std::error::Error;
 
 #[derive(GraphQLQuery)]
 #[graphql(
@@ -15,31 +16,43 @@ pub struct GetFileContentResult {
 }
 
 impl SourcegraphClient {
+    /// Served from the content cache when the file was already read at the same commit.
     pub async fn get_file_content(
         &self,
         repo: &str,
         rev: &str,
         path: &str,
-    ) -> Result<GetFileContentResult, Box<dyn Error>> {
+    ) -> Result<GetFileContentResult, SourcemaptError> {
+        let key = CacheKey {
+            repo: repo.to_owned(),
+            commit_oid: self.resolve_revision(repo, rev).await?,
+            path: path.to_owned(),
+        };
+
+        if let Some(content) = self.cache.get(&key) {
+            return Ok(GetFileContentResult { content });
+        }
+
         let variables = legacy_file_content::Variables {
             repo: repo.to_owned(),
-            rev: rev.to_owned(),
+            rev: key.commit_oid.clone(),
             path: path.to_owned(),
         };
 
         let response_data = self.post::<LegacyFileContent>(variables.into()).await?;
 
-        Ok(GetFileContentResult {
+        let result = GetFileContentResult {
             content: response_data
-                .data
-                .expect("missing data")
                 .repository
-                .expect("missing repository")
+                .ok_or_else(|| SourcemaptError::NotFound(format!("repository {}", repo)))?
                 .commit
-                .expect("missing commit")
+                .ok_or_else(|| SourcemaptError::NotFound(format!("revision {} of {}", rev, repo)))?
                 .file
-                .expect("missing file")
+                .ok_or_else(|| SourcemaptError::NotFound(format!("file {}", path)))?
                 .content,
-        })
+        };
+
+        self.cache.insert(key, result.content.clone());
+        Ok(result)
     }
 }
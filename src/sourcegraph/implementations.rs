@@ -0,0 +1,86 @@
+use crate::sourcegraph::client::SourcegraphClient;
+use crate::error::SourcemaptError;
+use crate::sourcegraph::references::{Location, Reference, REFERENCES_PAGE_SIZE};
+use graphql_client::GraphQLQuery;
+use serde::Serialize;
+
+#[derive(GraphQLQuery)]
+#[graphql(
+    schema_path = "src/sourcegraph/schema/code_intel_ext.graphql",
+    query_path = "src/sourcegraph/query/implementations.graphql",
+    response_derives = "Debug"
+)]
+struct Implementations;
+
+type GitObjectID = String;
+
+#[derive(Debug, Serialize)]
+pub struct GetImplementationsResult {
+    pub implementations: Vec<Reference>,
+    /// Pass this back to get the next page; absent on the last page.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub next: Option<String>,
+}
+
+impl SourcegraphClient {
+    pub async fn get_implementations(
+        &self,
+        repo: &str,
+        rev: &str,
+        path: &str,
+        line: u32,
+        char: u32,
+        after: Option<&str>,
+    ) -> Result<GetImplementationsResult, SourcemaptError> {
+        let variables = implementations::Variables {
+            repository: repo.to_owned(),
+            commit: rev.to_owned(),
+            path: path.to_owned(),
+            line: line as i64,
+            character: char as i64,
+            after: after.map(|v| v.to_owned()),
+            first: Some(REFERENCES_PAGE_SIZE as i64),
+        };
+
+        let response_body = self
+            .post::<Implementations>(variables.into())
+            .await?;
+
+        let lsif = response_body
+            .repository
+            .ok_or_else(|| SourcemaptError::NotFound(format!("repository {}", repo)))?
+            .commit
+            .ok_or_else(|| SourcemaptError::NotFound(format!("revision {} of {}", rev, repo)))?
+            .blob
+            .ok_or_else(|| SourcemaptError::NotFound(format!("file {}", path)))?
+            .lsif
+            .ok_or_else(|| SourcemaptError::NotFound(format!("code intelligence data for {}", path)))?;
+
+        let connection = lsif.implementations;
+
+        let locations = connection
+            .nodes
+            .into_iter()
+            .filter_map(|node| {
+                let range = node.range?;
+                Some(Location {
+                    repo: node.resource.repository.name,
+                    commit_oid: node.resource.commit.oid,
+                    path: node.resource.path,
+                    line: range.start.line,
+                    character: range.start.character,
+                })
+            })
+            .collect();
+
+        let implementations = self.with_previews(locations).await;
+
+        let next = if connection.page_info.has_next_page {
+            connection.page_info.end_cursor
+        } else {
+            None
+        };
+
+        Ok(GetImplementationsResult { implementations, next })
+    }
+}
@@ -0,0 +1,128 @@
+use crate::sourcegraph::client::SourcegraphClient;
+use crate::error::SourcemaptError;
+use graphql_client::GraphQLQuery;
+use serde::Serialize;
+
+use crate::sourcegraph::list_dir::list_dir::ListDirRepositoryCommitTreeEntriesOn;
+
+#[derive(GraphQLQuery)]
+#[graphql(
+    schema_path = "src/sourcegraph/schema/sourcegraph.graphql",
+    query_path = "src/sourcegraph/query/list_dir.graphql",
+    response_derives = "Debug"
+)]
+struct ListDir;
+
+pub const MAX_DIR_ENTRIES: usize = 200;
+
+#[derive(Debug, Serialize)]
+pub struct ListDirResult {
+    pub entries: Vec<DirEntry>,
+    /// Set when there were more than `MAX_DIR_ENTRIES` entries and the rest were left out.
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub truncated: bool,
+}
+
+#[derive(Debug, Serialize)]
+pub struct DirEntry {
+    pub path: String,
+    /// `file` or `dir`.
+    #[serde(rename = "type")]
+    pub kind: &'static str,
+    /// In bytes; absent for directories.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub size: Option<i64>,
+}
+
+impl SourcegraphClient {
+    /// The entries of the directory at `path`, and of its subdirectories down to `depth` levels
+    /// (1 lists only the directory itself).
+    pub async fn list_dir(
+        &self,
+        repo: &str,
+        rev: &str,
+        path: &str,
+        depth: usize,
+    ) -> Result<ListDirResult, SourcemaptError> {
+        let path = path.trim_matches('/');
+
+        let mut entries = Vec::new();
+        let mut level = vec![path.to_owned()];
+
+        for current_depth in 1..=depth {
+            let mut next_level = Vec::new();
+
+            for dir in level {
+                // One more than the limit is fetched to tell whether anything was left out
+                let remaining = MAX_DIR_ENTRIES + 1 - entries.len();
+                let children = self.list_dir_level(repo, rev, &dir, remaining).await?;
+
+                if current_depth < depth {
+                    next_level.extend(
+                        children
+                            .iter()
+                            .filter(|v| v.kind == "dir")
+                            .map(|v| v.path.clone()),
+                    );
+                }
+                entries.extend(children);
+
+                if entries.len() > MAX_DIR_ENTRIES {
+                    break;
+                }
+            }
+
+            if entries.len() > MAX_DIR_ENTRIES {
+                break;
+            }
+            level = next_level;
+        }
+
+        let truncated = entries.len() > MAX_DIR_ENTRIES;
+        entries.truncate(MAX_DIR_ENTRIES);
+
+        Ok(ListDirResult { entries, truncated })
+    }
+
+    /// The direct children of the directory at `path`, at most `first` of them.
+    async fn list_dir_level(
+        &self,
+        repo: &str,
+        rev: &str,
+        path: &str,
+        first: usize,
+    ) -> Result<Vec<DirEntry>, SourcemaptError> {
+        let variables = list_dir::Variables {
+            repository: repo.to_owned(),
+            commit: rev.to_owned(),
+            path: path.to_owned(),
+            recursive: Some(false),
+            first: Some(first as i64),
+        };
+
+        let response_body = self
+            .post::<ListDir>(variables.into())
+            .await?;
+
+        let entries = response_body
+            .repository
+            .ok_or_else(|| SourcemaptError::NotFound(format!("repository {}", repo)))?
+            .commit
+            .ok_or_else(|| SourcemaptError::NotFound(format!("revision {} of {}", rev, repo)))?
+            .tree
+            .ok_or_else(|| SourcemaptError::NotFound(format!("directory {}", path)))?
+            .entries;
+
+        Ok(entries
+            .into_iter()
+            .map(|v| DirEntry {
+                size: match &v.on {
+                    ListDirRepositoryCommitTreeEntriesOn::GitBlob(blob) => Some(blob.byte_size),
+                    _ => None,
+                },
+                kind: if v.is_directory { "dir" } else { "file" },
+                path: v.path,
+            })
+            .collect())
+    }
+}
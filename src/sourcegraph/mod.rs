@@ -1,5 +1,13 @@
+pub mod cache;
 pub mod client;
 pub mod definition_and_hover;
-pub mod error;
 pub mod file_content;
+pub mod implementations;
+pub mod list_dir;
+pub mod references;
+pub mod revision;
 pub mod search_files;
+pub mod symbols;
+
+#[cfg(test)]
+pub mod fake;
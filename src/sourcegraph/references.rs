@@ -0,0 +1,140 @@
+use crate::sourcegraph::client::SourcegraphClient;
+use crate::error::SourcemaptError;
+use graphql_client::GraphQLQuery;
+use serde::Serialize;
+use std::collections::HashMap;
+
+#[derive(GraphQLQuery)]
+#[graphql(
+    schema_path = "src/sourcegraph/schema/code_intel_ext.graphql",
+    query_path = "src/sourcegraph/query/references.graphql",
+    response_derives = "Debug"
+)]
+struct References;
+
+type GitObjectID = String;
+
+pub const REFERENCES_PAGE_SIZE: usize = 20;
+
+#[derive(Debug, Serialize)]
+pub struct GetReferencesResult {
+    pub references: Vec<Reference>,
+    /// Pass this back to get the next page; absent on the last page.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub next: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Reference {
+    pub repo: String,
+    pub path: String,
+    /// Zero-based, like the positions `!JUMP` takes.
+    pub line: i64,
+    pub character: i64,
+    pub preview: String,
+}
+
+pub(crate) struct Location {
+    pub repo: String,
+    pub commit_oid: String,
+    pub path: String,
+    pub line: i64,
+    pub character: i64,
+}
+
+impl SourcegraphClient {
+    pub async fn get_references(
+        &self,
+        repo: &str,
+        rev: &str,
+        path: &str,
+        line: u32,
+        char: u32,
+        after: Option<&str>,
+    ) -> Result<GetReferencesResult, SourcemaptError> {
+        let variables = references::Variables {
+            repository: repo.to_owned(),
+            commit: rev.to_owned(),
+            path: path.to_owned(),
+            line: line as i64,
+            character: char as i64,
+            after: after.map(|v| v.to_owned()),
+            first: Some(REFERENCES_PAGE_SIZE as i64),
+        };
+
+        let response_body = self
+            .post::<References>(variables.into())
+            .await?;
+
+        let lsif = response_body
+            .repository
+            .ok_or_else(|| SourcemaptError::NotFound(format!("repository {}", repo)))?
+            .commit
+            .ok_or_else(|| SourcemaptError::NotFound(format!("revision {} of {}", rev, repo)))?
+            .blob
+            .ok_or_else(|| SourcemaptError::NotFound(format!("file {}", path)))?
+            .lsif
+            .ok_or_else(|| SourcemaptError::NotFound(format!("code intelligence data for {}", path)))?;
+
+        let connection = lsif.references;
+
+        let locations = connection
+            .nodes
+            .into_iter()
+            .filter_map(|node| {
+                let range = node.range?;
+                Some(Location {
+                    repo: node.resource.repository.name,
+                    commit_oid: node.resource.commit.oid,
+                    path: node.resource.path,
+                    line: range.start.line,
+                    character: range.start.character,
+                })
+            })
+            .collect();
+
+        let references = self.with_previews(locations).await;
+
+        let next = if connection.page_info.has_next_page {
+            connection.page_info.end_cursor
+        } else {
+            None
+        };
+
+        Ok(GetReferencesResult { references, next })
+    }
+
+    /// Attaches the text of its line to each location. Each file is downloaded once, however many
+    /// locations it contains.
+    pub(crate) async fn with_previews(&self, locations: Vec<Location>) -> Vec<Reference> {
+        let mut files: HashMap<(String, String, String), Vec<String>> = HashMap::new();
+        let mut references = Vec::new();
+
+        for location in locations {
+            let key = (location.repo, location.commit_oid, location.path);
+            if !files.contains_key(&key) {
+                let lines: Vec<String> = self
+                    .get_file_content(&key.0, &key.1, &key.2)
+                    .await
+                    .map(|v| v.content.lines().map(|l| l.to_owned()).collect())
+                    .unwrap_or_default();
+                files.insert(key.clone(), lines);
+            }
+
+            let preview = files[&key]
+                .get(location.line as usize)
+                .map(|v| v.trim().to_owned())
+                .unwrap_or_default();
+
+            references.push(Reference {
+                repo: key.0,
+                path: key.2,
+                line: location.line,
+                character: location.character,
+                preview,
+            });
+        }
+
+        references
+    }
+}
@@ -0,0 +1,49 @@
+use crate::sourcegraph::client::SourcegraphClient;
+use crate::error::SourcemaptError;
+use graphql_client::GraphQLQuery;
+
+#[derive(GraphQLQuery)]
+#[graphql(
+    schema_path = "src/sourcegraph/schema/sourcegraph.graphql",
+    query_path = "src/sourcegraph/query/resolve_revision.graphql",
+    response_derives = "Debug"
+)]
+struct ResolveRevision;
+
+type GitObjectID = String;
+
+impl SourcegraphClient {
+    /// The commit OID `rev` points to. Each revision is only resolved once per client, and full
+    /// OIDs are returned as they are.
+    pub async fn resolve_revision(&self, repo: &str, rev: &str) -> Result<String, SourcemaptError> {
+        if is_oid(rev) {
+            return Ok(rev.to_owned());
+        }
+
+        let key = (repo.to_owned(), rev.to_owned());
+        if let Some(oid) = self.revisions.lock().unwrap().get(&key) {
+            return Ok(oid.clone());
+        }
+
+        let variables = resolve_revision::Variables {
+            repo: repo.to_owned(),
+            rev: rev.to_owned(),
+        };
+
+        let oid = self
+            .post::<ResolveRevision>(variables.into())
+            .await?
+            .repository
+            .ok_or_else(|| SourcemaptError::NotFound(format!("repository {}", repo)))?
+            .commit
+            .ok_or_else(|| SourcemaptError::NotFound(format!("revision {} of {}", rev, repo)))?
+            .oid;
+
+        self.revisions.lock().unwrap().insert(key, oid.clone());
+        Ok(oid)
+    }
+}
+
+pub fn is_oid(rev: &str) -> bool {
+    rev.len() == 40 && rev.chars().all(|c| c.is_ascii_hexdigit())
+}
@@ -1,7 +1,7 @@
+use crate::error::SourcemaptError;
 use crate::sourcegraph::client::SourcegraphClient;
 use graphql_client::GraphQLQuery;
 use serde::{Deserialize, Serialize};
-use std::error::Error;
 
 use crate::sourcegraph::search_files::search_files::SearchFilesSearchResultsResults;
 
@@ -15,29 +15,30 @@ struct SearchFiles;
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct SearchFilesResult {
-    files: Vec<SearchFilesFileMatch>,
+    pub files: Vec<SearchFilesFileMatch>,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct SearchFilesFileMatch {
-    path: String,
-    url: String,
-    lines: Vec<SearchFilesFileLine>,
+    pub path: String,
+    pub url: String,
+    pub lines: Vec<SearchFilesFileLine>,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct SearchFilesFileLine {
-    line_number: u32,
-    preview: String,
+    pub line_number: u32,
+    pub preview: String,
 }
 
 impl SourcegraphClient {
     pub async fn search_files(
         &self,
         repo: &str,
+        rev: &str,
         search_terms: &[String],
-    ) -> Result<SearchFilesResult, Box<dyn Error>> {
-        let query = format!("repo:^{}$", repo.replace(".", r#"\."#));
+    ) -> Result<SearchFilesResult, SourcemaptError> {
+        let query = format!("repo:^{}$@{}", repo.replace(".", r#"\."#), rev);
 
         let query = format!(
             "{} {}",
@@ -53,11 +54,8 @@ impl SourcegraphClient {
 
         let variables = search_files::Variables { query };
 
-        let response_data = self.post::<SearchFiles>(variables.into()).await?
-            .data.expect("missing response data");
+        let response_data = self.post::<SearchFiles>(variables.into()).await?;
 
-        // let response_data: search_files::ResponseData =
-        //     response_body.data.expect("missing response data");
         let output = map(response_data);
         Ok(output)
     }
@@ -0,0 +1,102 @@
+use crate::sourcegraph::client::SourcegraphClient;
+use crate::error::SourcemaptError;
+use graphql_client::GraphQLQuery;
+use serde::Serialize;
+
+use crate::sourcegraph::symbols::symbols::SymbolKind;
+
+#[derive(GraphQLQuery)]
+#[graphql(
+    schema_path = "src/sourcegraph/schema/sourcegraph.graphql",
+    query_path = "src/sourcegraph/query/symbols.graphql",
+    response_derives = "Debug"
+)]
+struct Symbols;
+
+pub const MAX_SYMBOLS: usize = 30;
+
+#[derive(Debug, Serialize)]
+pub struct GetSymbolsResult {
+    pub symbols: Vec<Symbol>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Symbol {
+    pub name: String,
+    /// Lowercase LSP symbol kind, such as `function`, `method` or `struct`.
+    pub kind: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub container: Option<String>,
+    pub path: String,
+    /// Zero-based, like the positions `!JUMP` takes.
+    pub line: i64,
+    pub character: i64,
+}
+
+impl SourcegraphClient {
+    /// Symbols whose name matches `query`, optionally only those of one `kind`.
+    pub async fn get_symbols(
+        &self,
+        repo: &str,
+        rev: &str,
+        query: &str,
+        kind: Option<&str>,
+    ) -> Result<GetSymbolsResult, SourcemaptError> {
+        // The kind is filtered here rather than by the server, so ask for extra symbols to make up
+        // for the ones that are dropped
+        let first = match kind {
+            Some(_) => MAX_SYMBOLS * 4,
+            None => MAX_SYMBOLS,
+        };
+
+        let variables = symbols::Variables {
+            repository: repo.to_owned(),
+            commit: rev.to_owned(),
+            query: query.to_owned(),
+            first: Some(first as i64),
+        };
+
+        let response_body = self
+            .post::<Symbols>(variables.into())
+            .await?;
+
+        let nodes = response_body
+            .repository
+            .ok_or_else(|| SourcemaptError::NotFound(format!("repository {}", repo)))?
+            .commit
+            .ok_or_else(|| SourcemaptError::NotFound(format!("revision {} of {}", rev, repo)))?
+            .symbols
+            .nodes;
+
+        let symbols = nodes
+            .into_iter()
+            .map(|node| {
+                let (line, character) = node
+                    .location
+                    .range
+                    .map(|v| (v.start.line, v.start.character))
+                    .unwrap_or_default();
+
+                Symbol {
+                    name: node.name,
+                    kind: kind_name(node.kind),
+                    container: node.container_name.filter(|v| !v.is_empty()),
+                    path: node.location.resource.path,
+                    line,
+                    character,
+                }
+            })
+            .filter(|v| kind.map_or(true, |kind| v.kind.eq_ignore_ascii_case(kind)))
+            .take(MAX_SYMBOLS)
+            .collect();
+
+        Ok(GetSymbolsResult { symbols })
+    }
+}
+
+fn kind_name(kind: SymbolKind) -> String {
+    match kind {
+        SymbolKind::Other(v) => v.to_lowercase(),
+        v => format!("{:?}", v).to_lowercase(),
+    }
+}
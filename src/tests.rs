@@ -0,0 +1,853 @@
+use crate::code_host::fake::FakeCodeHost;
+use crate::code_host::local_git::LocalGitHost;
+use crate::consts;
+use crate::error::SourcemaptError;
+use crate::llm::mock::ScriptedBackend;
+use crate::llm::{ChatMessage, ChatRole, Completion, Protocol, ToolCall};
+use crate::message::SourcemaptMessage;
+use crate::sourcegraph::client::{SourcegraphClient, SourcegraphConfig};
+use crate::sourcegraph::definition_and_hover::{DefinitionNode, Range, Resource};
+use crate::sourcegraph::fake::FakeSourcegraph;
+use crate::types::tests::{code_block, SYNCED_CODE};
+use crate::types::Command;
+use crate::{format_definitions, Sourcemapt, KEEP_RECENT_MESSAGES, MAX_JUMP_DEFINITIONS};
+use serde_json::json;
+use std::collections::HashMap;
+use std::path::PathBuf;
+
+const SUMMARY: &str = "IN SUMMARY: `StatesHasBeenSynced` reports whether the reconciler has populated the actual state of the world.";
+
+fn fake_sourcegraph() -> FakeSourcegraph {
+    let content = (1..=700)
+        .map(|i| format!("line {}", i))
+        .collect::<Vec<String>>()
+        .join("\n");
+
+    let mut responses = HashMap::new();
+    responses.insert(
+        "SearchFiles".to_owned(),
+        json!({
+            "search": {
+                "results": {
+                    "results": [{
+                        "__typename": "FileMatch",
+                        "file": {
+                            "path": "pkg/kubelet/volumemanager/reconciler/reconstruct_common.go",
+                            "url": "/github.com/kubernetes/kubernetes/-/blob/pkg/kubelet/volumemanager/reconciler/reconstruct_common.go",
+                        },
+                        "lineMatches": [{
+                            "preview": "func (rc *reconciler) StatesHasBeenSynced() bool {",
+                            "lineNumber": 79,
+                        }],
+                    }],
+                },
+            },
+        }),
+    );
+    responses.insert(
+        "ResolveRevision".to_owned(),
+        json!({
+            "repository": {
+                "commit": {
+                    "oid": "8f3c2a1b7d9e4f6a0b5c3d2e1f0a9b8c7d6e5f4a",
+                },
+            },
+        }),
+    );
+    responses.insert(
+        "LegacyFileContent".to_owned(),
+        json!({
+            "repository": {
+                "commit": {
+                    "file": {
+                        "__typename": "GitBlob",
+                        "content": content,
+                    },
+                },
+            },
+        }),
+    );
+
+    FakeSourcegraph::start(responses)
+}
+
+/// Replays two recorded model turns and a summary against a fake Sourcegraph and checks that
+/// every command was executed and its result fed back into the history in order.
+#[tokio::test]
+async fn run_loop_replays_recorded_session() {
+    let sourcegraph = fake_sourcegraph();
+    let backend = ScriptedBackend::new(vec![consts::TEST1, consts::TEST2, SUMMARY]);
+
+    let mut sourcemapt = Sourcemapt::new(
+        Box::new(backend),
+        Box::new(
+            SourcegraphClient::new(&SourcegraphConfig {
+                endpoint: Some(sourcegraph.endpoint()),
+                ..Default::default()
+            })
+                .unwrap(),
+        ),
+        "github.com/kubernetes/kubernetes".to_owned(),
+        "master".to_owned(),
+    );
+    // One command at a time, so the fake sees the operations in a fixed order
+    sourcemapt.budget.max_concurrent_commands = 1;
+    sourcemapt.add_system();
+
+    let error = sourcemapt
+        .run_loop("How does the volume manager know its reconciler has synced?".to_owned())
+        .await;
+    assert!(error.is_none());
+
+    let messages = &sourcemapt.messages;
+    assert_eq!(messages.len(), 16);
+
+    assert!(matches!(messages[0], SourcemaptMessage::System { .. }));
+    assert!(matches!(messages[1], SourcemaptMessage::User { .. }));
+
+    match &messages[2] {
+        SourcemaptMessage::CommandInvocation { command, .. } => assert_eq!(
+            command,
+            &Command::SearchFiles {
+                keywords: vec!["kubelet volume manager reconciler synced".to_owned()],
+            }
+        ),
+        other => panic!("expected a command invocation, got {}", other),
+    }
+    match &messages[5] {
+        SourcemaptMessage::CommandInvocation { command, .. } => assert_eq!(
+            command,
+            &Command::ReadLines {
+                file: "pkg/kubelet/volumemanager/volume_manager.go".to_owned(),
+                start: 636,
+                n: 15,
+            }
+        ),
+        other => panic!("expected a command invocation, got {}", other),
+    }
+
+    match &messages[6] {
+        SourcemaptMessage::CommandResult { content, .. } => {
+            assert!(content.contains("reconstruct_common.go"))
+        }
+        other => panic!("expected a command result, got {}", other),
+    }
+    match &messages[8] {
+        SourcemaptMessage::Code { code, .. } => {
+            assert_eq!(code.start, 636);
+            assert_eq!(code.lines.len(), 15);
+            assert_eq!(code.lines[0], "line 637");
+        }
+        other => panic!("expected code, got {}", other),
+    }
+    match &messages[14] {
+        SourcemaptMessage::Code { code, .. } => {
+            assert_eq!(code.start, 79);
+            assert_eq!(code.lines, vec!["line 80", "line 81", "line 82", "line 83", "line 84"]);
+        }
+        other => panic!("expected code, got {}", other),
+    }
+
+    assert!(messages[15].is_summary());
+    assert!(messages.iter().all(|v| !v.hidden()));
+
+    assert_eq!(
+        sourcegraph.operations(),
+        vec![
+            "SearchFiles",
+            "SearchFiles",
+            "ResolveRevision",
+            "LegacyFileContent",
+            "SearchFiles",
+            "LegacyFileContent",
+        ]
+    );
+}
+
+/// A `Sourcemapt` that never reaches a network: the backend replays `completions` and the code
+/// host is a local checkout that the tests don't read from.
+fn offline_sourcemapt(completions: Vec<&str>) -> Sourcemapt {
+    offline_sourcemapt_with(ScriptedBackend::new(completions))
+}
+
+fn offline_sourcemapt_with(backend: ScriptedBackend) -> Sourcemapt {
+    Sourcemapt::new(
+        Box::new(backend),
+        Box::new(LocalGitHost::new(PathBuf::from("."))),
+        "github.com/example/repo".to_owned(),
+        "main".to_owned(),
+    )
+}
+
+/// A completion calling each `(call ID, tool, arguments)`.
+fn tool_calls(calls: &[(&str, &str, &str)]) -> Completion {
+    Completion {
+        content: String::new(),
+        tool_calls: calls
+            .iter()
+            .map(|(id, name, arguments)| ToolCall {
+                id: id.to_string(),
+                name: name.to_string(),
+                arguments: arguments.to_string(),
+            })
+            .collect(),
+    }
+}
+
+fn invocation(command: Command, call_id: &str) -> SourcemaptMessage {
+    SourcemaptMessage::CommandInvocation {
+        command,
+        hidden: false,
+        call_id: Some(call_id.to_owned()),
+    }
+}
+
+fn result(content: &str, call_id: &str) -> SourcemaptMessage {
+    SourcemaptMessage::CommandResult {
+        content: content.to_owned(),
+        hidden: false,
+        call_id: Some(call_id.to_owned()),
+    }
+}
+
+/// The tools API rejects a request with a tool call that has no result, or a result for a call
+/// it hasn't seen.
+fn assert_tool_calls_paired(messages: &[ChatMessage]) {
+    let mut pending: Vec<String> = Vec::new();
+
+    for message in messages {
+        match message.role {
+            ChatRole::Tool => {
+                let id = message.tool_call_id.clone().expect("tool message without a call ID");
+                assert!(pending.contains(&id), "result for unknown call {}", id);
+                pending.retain(|v| v != &id);
+            }
+            _ => {
+                assert!(pending.is_empty(), "calls without results: {:?}", pending);
+                pending.extend(message.tool_calls.iter().map(|v| v.id.clone()));
+            }
+        }
+    }
+
+    assert!(pending.is_empty(), "calls without results: {:?}", pending);
+}
+
+#[test]
+fn close_scope_keeps_tool_calls_paired() {
+    let mut sourcemapt = offline_sourcemapt(vec![]);
+    sourcemapt.protocol = Protocol::Tools;
+    sourcemapt.add_system();
+    sourcemapt.messages.extend([
+        SourcemaptMessage::User {
+            content: "Where is the kiwi manager?".to_owned(),
+            hidden: false,
+        },
+        invocation(Command::Scope { name: "find the kiwi manager".to_owned() }, "call_1"),
+        result("Started scope `find the kiwi manager`", "call_1"),
+        invocation(Command::ListDir { path: "pkg".to_owned(), depth: 1 }, "call_2"),
+        result(r#"{"entries":[]}"#, "call_2"),
+        invocation(Command::Decide { rationale: "It's in pkg/kiwi".to_owned() }, "call_3"),
+    ]);
+
+    sourcemapt.close_scope(6);
+    sourcemapt.messages.push(SourcemaptMessage::Decision {
+        scope: "find the kiwi manager".to_owned(),
+        rationale: "It's in pkg/kiwi".to_owned(),
+        hidden: false,
+        call_id: Some("call_3".to_owned()),
+    });
+
+    assert!(!sourcemapt.messages[2].hidden());
+    assert!(!sourcemapt.messages[3].hidden());
+    assert!(sourcemapt.messages[4].hidden());
+    assert!(sourcemapt.messages[5].hidden());
+    assert_tool_calls_paired(&sourcemapt.prompt_messages());
+}
+
+/// A scope opened and decided in one response: the `!SCOPE` result only joins the history
+/// after the scope is closed.
+#[test]
+fn close_scope_in_one_turn_keeps_tool_calls_paired() {
+    let mut sourcemapt = offline_sourcemapt(vec![]);
+    sourcemapt.protocol = Protocol::Tools;
+    sourcemapt.add_system();
+    sourcemapt.messages.extend([
+        SourcemaptMessage::User {
+            content: "Where is the kiwi manager?".to_owned(),
+            hidden: false,
+        },
+        invocation(Command::Scope { name: "guess".to_owned() }, "call_1"),
+        invocation(Command::Decide { rationale: "Nothing to explore".to_owned() }, "call_2"),
+    ]);
+
+    sourcemapt.close_scope(3);
+    sourcemapt.messages.extend([
+        result("Started scope `guess`", "call_1"),
+        SourcemaptMessage::Decision {
+            scope: "guess".to_owned(),
+            rationale: "Nothing to explore".to_owned(),
+            hidden: false,
+            call_id: Some("call_2".to_owned()),
+        },
+    ]);
+
+    assert_tool_calls_paired(&sourcemapt.prompt_messages());
+}
+
+/// A `!DECIDE` followed by a new `!SCOPE` in the same response closes the scope it was written
+/// in, not the new one.
+#[tokio::test]
+async fn decide_closes_the_scope_it_was_written_in() {
+    let mut sourcemapt = offline_sourcemapt_with(ScriptedBackend::from_completions([
+        tool_calls(&[("call_1", "scope", r#"{"name": "find the kiwi manager"}"#)]),
+        tool_calls(&[
+            ("call_2", "decide", r#"{"rationale": "It's in pkg/kiwi"}"#),
+            ("call_3", "scope", r#"{"name": "read the kiwi manager"}"#),
+        ]),
+        Completion {
+            content: SUMMARY.to_owned(),
+            tool_calls: Vec::new(),
+        },
+    ]));
+    sourcemapt.protocol = Protocol::Tools;
+    sourcemapt.add_system();
+
+    let error = sourcemapt.run_loop("Where are kiwis managed?".to_owned()).await;
+    assert!(error.is_none());
+
+    let decision = sourcemapt.messages.iter().find_map(|v| match v {
+        SourcemaptMessage::Decision { scope, .. } => Some(scope.as_str()),
+        _ => None,
+    });
+    assert_eq!(decision, Some("find the kiwi manager"));
+
+    // The new scope's invocation and result are untouched
+    let new_scope = sourcemapt
+        .messages
+        .iter()
+        .filter(|v| match v {
+            SourcemaptMessage::CommandInvocation { call_id, .. }
+            | SourcemaptMessage::CommandResult { call_id, .. } => call_id.as_deref() == Some("call_3"),
+            _ => false,
+        })
+        .collect::<Vec<_>>();
+    assert_eq!(new_scope.len(), 2);
+    assert!(new_scope.iter().all(|v| !v.hidden()));
+    assert_tool_calls_paired(&sourcemapt.prompt_messages());
+}
+
+/// A command the model gets wrong is answered with its parse error, and the session carries on.
+#[tokio::test]
+async fn run_loop_reports_unparseable_commands_to_the_model() {
+    let mut sourcemapt = offline_sourcemapt(vec![
+        r#"!READ_LINES "pkg/kiwilet/kiwilet.go" "one" "10""#,
+        SUMMARY,
+    ]);
+    sourcemapt.add_system();
+
+    let error = sourcemapt.run_loop("Where are kiwis managed?".to_owned()).await;
+    assert!(error.is_none());
+
+    let messages = &sourcemapt.messages;
+    assert_eq!(messages.len(), 5);
+
+    match &messages[2] {
+        SourcemaptMessage::CommandInvocation { command: Command::Invalid { text, .. }, .. } => {
+            assert_eq!(text, r#"!READ_LINES "pkg/kiwilet/kiwilet.go" "one" "10""#)
+        }
+        other => panic!("expected an invalid command, got {}", other),
+    }
+    match &messages[3] {
+        SourcemaptMessage::CommandResult { content, .. } => {
+            let content: serde_json::Value = serde_json::from_str(content).unwrap();
+            assert!(content["error"].as_str().unwrap().contains("Invalid number \"one\""));
+        }
+        other => panic!("expected a command result, got {}", other),
+    }
+
+    assert!(messages[4].is_summary());
+    assert_eq!(sourcemapt.consecutive_errors, 1);
+}
+
+/// Giving up on a run of failed commands still answers every tool call of the turn, so the next
+/// question can be sent.
+#[tokio::test]
+async fn run_loop_keeps_tool_calls_paired_when_giving_up_on_errors() {
+    let mut sourcemapt = offline_sourcemapt_with(ScriptedBackend::from_completions([
+        tool_calls(&[
+            ("call_1", "read_lines", r#"{"file": "pkg/kiwilet/kiwilet.go"}"#),
+            ("call_2", "scope", r#"{"name": "find the kiwi manager"}"#),
+        ]),
+        Completion {
+            content: SUMMARY.to_owned(),
+            tool_calls: Vec::new(),
+        },
+    ]));
+    sourcemapt.protocol = Protocol::Tools;
+    sourcemapt.budget.max_consecutive_errors = 0;
+    sourcemapt.add_system();
+
+    let error = sourcemapt.run_loop("Where are kiwis managed?".to_owned()).await;
+    assert!(matches!(error, Some(SourcemaptError::InvalidCommand(_))));
+    assert_eq!(sourcemapt.messages.len(), 6);
+    assert_tool_calls_paired(&sourcemapt.prompt_messages());
+
+    let error = sourcemapt.run_loop("Try again?".to_owned()).await;
+    assert!(error.is_none());
+    assert_tool_calls_paired(&sourcemapt.prompt_messages());
+}
+
+/// Running out of model calls keeps the results of the commands that already ran.
+#[tokio::test]
+async fn run_loop_keeps_tool_calls_paired_when_out_of_turns() {
+    let mut sourcemapt = offline_sourcemapt_with(ScriptedBackend::from_completions([tool_calls(&[
+        ("call_1", "scope", r#"{"name": "find the kiwi manager"}"#),
+    ])]));
+    sourcemapt.protocol = Protocol::Tools;
+    sourcemapt.budget.max_turns = 1;
+    sourcemapt.add_system();
+
+    let error = sourcemapt.run_loop("Where are kiwis managed?".to_owned()).await;
+    assert!(matches!(error, Some(SourcemaptError::Budget(_))));
+    match sourcemapt.messages.last() {
+        Some(SourcemaptMessage::CommandResult { content, call_id, .. }) => {
+            assert_eq!(content, "Started scope `find the kiwi manager`");
+            assert_eq!(call_id.as_deref(), Some("call_1"));
+        }
+        other => panic!("expected the scope's result, got {:?}", other.map(|v| v.to_string())),
+    }
+    assert_tool_calls_paired(&sourcemapt.prompt_messages());
+}
+
+fn fake_host_sourcemapt(backend: ScriptedBackend, host: FakeCodeHost) -> Sourcemapt {
+    Sourcemapt::new(
+        Box::new(backend),
+        Box::new(host),
+        "github.com/example/repo".to_owned(),
+        "main".to_owned(),
+    )
+}
+
+/// The read-only commands of a response are run while it streams in; scopes and lines that
+/// don't parse wait for the full response.
+#[tokio::test]
+async fn stream_completion_dispatches_read_only_commands() {
+    let host = FakeCodeHost::default();
+    let calls = host.calls();
+    let sourcemapt = fake_host_sourcemapt(
+        ScriptedBackend::new(vec![[
+            r#"!SCOPE "find the kiwi manager""#,
+            r#"!READ_LINES "pkg/kiwi.go" "0" "2""#,
+            r#"!READ_LINES "pkg/kiwi.go" "one" "2""#,
+            r#"!LIST_DIR "pkg" "1""#,
+        ]
+            .join("\n")]),
+        host,
+    );
+
+    let (_, dispatched) = sourcemapt.stream_completion(&[], &[]).await.unwrap();
+
+    assert_eq!(
+        dispatched.iter().map(|v| v.command.clone()).collect::<Vec<_>>(),
+        vec![
+            Command::ReadLines { file: "pkg/kiwi.go".to_owned(), start: 0, n: 2 },
+            Command::ListDir { path: "pkg".to_owned(), depth: 1 },
+        ]
+    );
+    assert!(dispatched.iter().all(|v| v.result.is_ok()));
+    assert_eq!(*calls.borrow(), vec!["read pkg/kiwi.go", "list pkg"]);
+}
+
+/// Commands run while the response streamed in aren't run again, and each result, including
+/// those of a repeated line, follows the invocation it belongs to.
+#[tokio::test]
+async fn run_loop_runs_dispatched_commands_once() {
+    let host = FakeCodeHost::default();
+    let calls = host.calls();
+    let mut sourcemapt = fake_host_sourcemapt(
+        ScriptedBackend::new(vec![
+            [
+                "Let me look at both files.",
+                r#"!READ_LINES "pkg/kiwi.go" "0" "2""#,
+                r#"!LIST_DIR "pkg" "1""#,
+                r#"!READ_LINES "pkg/manager.go" "3" "2""#,
+                r#"!READ_LINES "pkg/kiwi.go" "0" "2""#,
+            ]
+                .join("\n"),
+            SUMMARY.to_owned(),
+        ]),
+        host,
+    );
+    sourcemapt.add_system();
+
+    let error = sourcemapt.run_loop("Where are kiwis managed?".to_owned()).await;
+    assert!(error.is_none());
+
+    assert_eq!(
+        *calls.borrow(),
+        vec!["read pkg/kiwi.go", "list pkg", "read pkg/manager.go", "read pkg/kiwi.go"]
+    );
+    assert!(sourcemapt.dispatched.is_empty());
+
+    let messages = &sourcemapt.messages;
+    assert_eq!(messages.len(), 12);
+    for i in 3..7 {
+        let command = match &messages[i] {
+            SourcemaptMessage::CommandInvocation { command, .. } => command,
+            other => panic!("expected a command invocation, got {}", other),
+        };
+        match (command, &messages[i + 4]) {
+            (Command::ReadLines { file, start, .. }, SourcemaptMessage::Code { code, .. }) => {
+                assert_eq!(&code.path, file);
+                assert_eq!(code.start, *start);
+                assert_eq!(code.lines[0], format!("{} line {}", file, start));
+            }
+            (Command::ListDir { path, .. }, SourcemaptMessage::CommandResult { content, .. }) => {
+                assert!(content.contains(&format!("{}/kiwi.go", path)));
+            }
+            (command, result) => panic!("{} was answered with {}", command.serialize(), result),
+        }
+    }
+    assert!(messages[11].is_summary());
+}
+
+/// Commands that run concurrently and finish out of order still have their results recorded in
+/// the order they were called, each with its own call ID.
+#[tokio::test]
+async fn process_responses_keeps_results_in_order_when_commands_finish_out_of_order() {
+    let host = FakeCodeHost::default().hold("pkg/kiwi.go", "pkg/store.go");
+    let calls = host.calls();
+    let mut sourcemapt = fake_host_sourcemapt(
+        ScriptedBackend::from_completions([
+            tool_calls(&[
+                ("call_1", "read_lines", r#"{"file": "pkg/kiwi.go", "start": 0, "n": 2}"#),
+                ("call_2", "read_lines", r#"{"file": "pkg/manager.go", "start": 0, "n": 2}"#),
+                ("call_3", "read_lines", r#"{"file": "pkg/store.go", "start": 0, "n": 2}"#),
+            ]),
+            Completion {
+                content: SUMMARY.to_owned(),
+                tool_calls: Vec::new(),
+            },
+        ]),
+        host,
+    );
+    sourcemapt.protocol = Protocol::Tools;
+    sourcemapt.budget.max_concurrent_commands = 3;
+    sourcemapt.add_system();
+
+    let error = sourcemapt.run_loop("Where are kiwis managed?".to_owned()).await;
+    assert!(error.is_none());
+
+    assert_eq!(
+        *calls.borrow(),
+        vec!["read pkg/manager.go", "read pkg/store.go", "read pkg/kiwi.go"]
+    );
+
+    let results = sourcemapt
+        .messages
+        .iter()
+        .filter_map(|v| match v {
+            SourcemaptMessage::Code { code, call_id, .. } => {
+                Some((code.path.as_str(), call_id.as_deref().unwrap()))
+            }
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+    assert_eq!(
+        results,
+        vec![
+            ("pkg/kiwi.go", "call_1"),
+            ("pkg/manager.go", "call_2"),
+            ("pkg/store.go", "call_3"),
+        ]
+    );
+    assert_tool_calls_paired(&sourcemapt.prompt_messages());
+}
+
+/// A question answered over several tool calls: six large reads, a decision, then a short
+/// latest exchange of exactly `KEEP_RECENT_MESSAGES` messages.
+fn long_history() -> Sourcemapt {
+    let mut sourcemapt = offline_sourcemapt(vec![]);
+    sourcemapt.protocol = Protocol::Tools;
+    sourcemapt.add_system();
+    sourcemapt.messages.push(SourcemaptMessage::User {
+        content: "Where are kiwis managed?".to_owned(),
+        hidden: false,
+    });
+
+    let read = |start| Command::ReadLines { file: "pkg/kiwi.go".to_owned(), start, n: 100 };
+    for i in 0..6 {
+        let call_id = format!("read_{}", i);
+        sourcemapt.messages.push(invocation(read(i * 100), &call_id));
+        sourcemapt.messages.push(result(&"kiwi ".repeat(400), &call_id));
+    }
+
+    sourcemapt.messages.push(invocation(Command::Decide { rationale: "pkg/kiwi.go".to_owned() }, "decide"));
+    sourcemapt.messages.push(SourcemaptMessage::Decision {
+        scope: crate::DEFAULT_SCOPE.to_owned(),
+        rationale: "pkg/kiwi.go".to_owned(),
+        hidden: false,
+        call_id: Some("decide".to_owned()),
+    });
+
+    sourcemapt.messages.extend([
+        invocation(read(700), "recent_1"),
+        result("func manageKiwis() {}", "recent_1"),
+        invocation(read(800), "recent_2"),
+        result("func (k *kiwi) Manage() {}", "recent_2"),
+        SourcemaptMessage::Model {
+            content: "Kiwis are managed by manageKiwis.".to_owned(),
+            hidden: false,
+        },
+        SourcemaptMessage::Injected {
+            kind: crate::types::InjectedMessage::AskToSummarize,
+            hidden: false,
+        },
+    ]);
+
+    sourcemapt
+}
+
+fn assert_compacted(sourcemapt: &Sourcemapt, tail: &[SourcemaptMessage]) {
+    let messages = &sourcemapt.messages;
+    let recent = &messages[messages.len() - KEEP_RECENT_MESSAGES..];
+
+    assert!(recent.iter().all(|v| !v.hidden()), "the latest exchange was hidden");
+    assert_eq!(
+        recent.iter().map(|v| v.to_string()).collect::<Vec<_>>(),
+        tail.iter().map(|v| v.to_string()).collect::<Vec<_>>()
+    );
+
+    for message in messages {
+        if let SourcemaptMessage::System { .. }
+        | SourcemaptMessage::User { .. }
+        | SourcemaptMessage::Decision { .. } = message
+        {
+            assert!(!message.hidden(), "hid {}", message);
+        }
+    }
+
+    assert!(sourcemapt.prompt_tokens() <= sourcemapt.budget.max_prompt_tokens);
+    assert_tool_calls_paired(&sourcemapt.prompt_messages());
+}
+
+#[test]
+fn compact_to_budget_elides_old_output_first() {
+    let mut sourcemapt = long_history();
+    let tail = sourcemapt.messages[sourcemapt.messages.len() - KEEP_RECENT_MESSAGES..].to_vec();
+
+    // Room for everything but the large reads
+    let large = sourcemapt.messages
+        .iter()
+        .filter(|v| matches!(v, SourcemaptMessage::CommandResult { content, .. } if content.len() > 1000))
+        .map(|v| v.token_count())
+        .sum::<usize>();
+    sourcemapt.budget.max_prompt_tokens = sourcemapt.prompt_tokens() - large + 200;
+
+    sourcemapt.compact_to_budget();
+
+    assert_compacted(&sourcemapt, &tail);
+    // Eliding was enough, so every invocation is still visible
+    assert!(sourcemapt.messages
+        .iter()
+        .filter(|v| matches!(v, SourcemaptMessage::CommandInvocation { .. }))
+        .all(|v| !v.hidden()));
+}
+
+#[test]
+fn compact_to_budget_hides_old_invocations_when_eliding_is_not_enough() {
+    let mut sourcemapt = long_history();
+    let tail = sourcemapt.messages[sourcemapt.messages.len() - KEEP_RECENT_MESSAGES..].to_vec();
+
+    // Room for little more than what has to stay: the system prompt, the question, the decision
+    // and the latest exchange
+    let kept = sourcemapt.messages
+        .iter()
+        .filter(|v| !matches!(v, SourcemaptMessage::CommandInvocation { command: Command::ReadLines { start, .. }, .. } if *start < 700))
+        .filter(|v| !matches!(v, SourcemaptMessage::CommandResult { content, .. } if content.len() > 1000))
+        .map(|v| v.token_count())
+        .sum::<usize>();
+    sourcemapt.budget.max_prompt_tokens = kept + 10;
+
+    sourcemapt.compact_to_budget();
+
+    assert_compacted(&sourcemapt, &tail);
+}
+
+/// The prompt is compacted after the new messages join the history and before it's sent.
+#[tokio::test]
+async fn call_gpt4_sends_prompt_within_budget() {
+    let mut sourcemapt = long_history();
+    sourcemapt.backend = Box::new(ScriptedBackend::new(vec![SUMMARY]));
+    sourcemapt.budget.max_prompt_tokens = 1500;
+    assert!(sourcemapt.prompt_tokens() > 1500);
+
+    let late = Command::ReadLines { file: "pkg/kiwi.go".to_owned(), start: 900, n: 100 };
+    let responses = sourcemapt
+        .call_gpt4(&[invocation(late, "late"), result(&"kiwi ".repeat(400), "late")])
+        .await
+        .unwrap()
+        .len();
+
+    let sent = &sourcemapt.messages[..sourcemapt.messages.len() - responses];
+    let sent_tokens = sent
+        .iter()
+        .filter(|v| !v.hidden())
+        .map(|v| v.token_count())
+        .sum::<usize>();
+    assert!(sent_tokens <= 1500, "sent ~{} tokens", sent_tokens);
+    assert!(sourcemapt.messages.last().unwrap().is_summary());
+}
+
+#[test]
+fn compact_to_budget_leaves_history_under_budget_alone() {
+    let mut sourcemapt = long_history();
+    sourcemapt.budget.max_prompt_tokens = sourcemapt.prompt_tokens();
+    let before = sourcemapt.messages.len();
+
+    sourcemapt.compact_to_budget();
+
+    assert_eq!(sourcemapt.messages.len(), before);
+    assert!(sourcemapt.messages.iter().all(|v| !v.hidden()));
+}
+
+/// A Sourcemapt that has shown `SYNCED_CODE` as lines 120-124 of pkg/reconciler.go.
+fn sourcemapt_with_code() -> Sourcemapt {
+    let mut sourcemapt = offline_sourcemapt(Vec::new());
+    let mut code = code_block(SYNCED_CODE);
+    code.start = 119;
+    sourcemapt.messages.push(SourcemaptMessage::Code {
+        code,
+        hidden: false,
+        call_id: None,
+    });
+    sourcemapt
+}
+
+#[test]
+fn replace_echoed_code_replaces_exact_echo() {
+    let sourcemapt = sourcemapt_with_code();
+    let text = format!("It locks first:\n```go\n{}\n```\nThen returns.", SYNCED_CODE.join("\n"));
+
+    assert_eq!(
+        sourcemapt.replace_echoed_code(&text),
+        "It locks first:\n(see pkg/reconciler.go:120-124)\nThen returns.",
+    );
+}
+
+#[test]
+fn replace_echoed_code_replaces_partial_echo() {
+    let sourcemapt = sourcemapt_with_code();
+    let text = format!("It locks first:\n```\n{}\n```", SYNCED_CODE[1..4].join("\n"));
+
+    assert_eq!(sourcemapt.replace_echoed_code(&text), "It locks first:\n(see pkg/reconciler.go:121-123)");
+}
+
+#[test]
+fn replace_echoed_code_keeps_similar_but_different_code() {
+    let sourcemapt = sourcemapt_with_code();
+    let mut lines = SYNCED_CODE.to_vec();
+    lines[3] = "\treturn rc.synced && rc.ready";
+    let text = format!("It should also check readiness:\n```go\n{}\n```", lines.join("\n"));
+
+    assert_eq!(sourcemapt.replace_echoed_code(&text), text);
+}
+
+#[test]
+fn replace_echoed_code_keeps_code_in_the_summary() {
+    let sourcemapt = sourcemapt_with_code();
+    let text = format!("{}\n```go\n{}\n```", SUMMARY, SYNCED_CODE.join("\n"));
+
+    assert_eq!(sourcemapt.replace_echoed_code(&text), text);
+}
+
+fn definition(repo: &str, path: &str, line: i64) -> DefinitionNode {
+    DefinitionNode {
+        resource: Resource {
+            path: path.to_owned(),
+            repo: repo.to_owned(),
+            commit_oid: "8f3c2a1b7d9e4f6a0b5c3d2e1f0a9b8c7d6e5f4a".to_owned(),
+        },
+        range: Range {
+            line_start: line,
+            char_start: 0,
+            line_end: line,
+            char_end: 10,
+        },
+    }
+}
+
+#[test]
+fn format_definitions_lists_every_definition_before_their_code() {
+    let definitions = vec![
+        definition("github.com/example/repo", "pkg/reconciler.go", 0),
+        definition("github.com/example/vendor", "pkg/reconciler.go", 41),
+    ];
+    let mut other = code_block(SYNCED_CODE);
+    other.start = 41;
+    other.repo = Some("github.com/example/vendor".to_owned());
+
+    let formatted = format_definitions(
+        "github.com/example/repo",
+        &definitions,
+        Some("func (rc *reconciler) StatesHasBeenSynced() bool".to_owned()),
+        &[Ok(code_block(SYNCED_CODE)), Ok(other.clone())],
+    );
+
+    assert_eq!(
+        formatted,
+        [
+            "func (rc *reconciler) StatesHasBeenSynced() bool".to_owned(),
+            [
+                "Found 2 definitions:",
+                "1. github.com/example/repo pkg/reconciler.go:1",
+                "2. github.com/example/vendor pkg/reconciler.go:42 (in another repository)",
+            ]
+                .join("\n"),
+            format!("Definition 1 (pkg/reconciler.go):\n{}", code_block(SYNCED_CODE).format()),
+            format!("Definition 2 (pkg/reconciler.go):\n{}", other.format()),
+        ]
+            .join("\n\n")
+    );
+}
+
+#[test]
+fn format_definitions_says_why_a_definition_is_unavailable() {
+    let formatted = format_definitions(
+        "github.com/example/repo",
+        &[definition("github.com/example/vendor", "pkg/reconciler.go", 41)],
+        None,
+        &[Err(SourcemaptError::NotFound("repository github.com/example/vendor".to_owned()))],
+    );
+
+    assert_eq!(
+        formatted,
+        [
+            "Found 1 definition:\n1. github.com/example/vendor pkg/reconciler.go:42 (in another repository)",
+            "Definition 1: github.com/example/vendor:pkg/reconciler.go (unavailable: not found: repository github.com/example/vendor)",
+        ]
+            .join("\n\n")
+    );
+}
+
+#[test]
+fn format_definitions_only_shows_the_first_definitions() {
+    let definitions = (0..MAX_JUMP_DEFINITIONS + 2)
+        .map(|i| definition("github.com/example/repo", &format!("pkg/reconciler_{}.go", i), 0))
+        .collect::<Vec<_>>();
+    let blocks = (0..MAX_JUMP_DEFINITIONS)
+        .map(|_| Ok(code_block(SYNCED_CODE)))
+        .collect::<Vec<Result<_, SourcemaptError>>>();
+
+    let formatted = format_definitions("github.com/example/repo", &definitions, None, &blocks);
+
+    assert!(formatted.starts_with(&format!("Found {} definitions:", MAX_JUMP_DEFINITIONS + 2)));
+    // Definitions past the cutoff are still listed
+    assert!(formatted.contains(&format!(
+        "{}. github.com/example/repo pkg/reconciler_{}.go:1",
+        MAX_JUMP_DEFINITIONS + 2,
+        MAX_JUMP_DEFINITIONS + 1
+    )));
+    assert_eq!(formatted.matches("Definition ").count(), MAX_JUMP_DEFINITIONS);
+    assert!(formatted.ends_with(&format!("Only the first {} definitions are shown.", MAX_JUMP_DEFINITIONS)));
+}
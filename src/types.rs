@@ -1,12 +1,47 @@
-use std::error::Error;
 use std::fmt;
 use std::str::FromStr;
 use regex::Regex;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
 use crate::consts;
+use crate::error::SourcemaptError;
+use crate::llm::ToolDefinition;
 
+#[derive(Serialize, Deserialize)]
 pub struct CodeBlock {
+    /// The file the lines were read from.
+    #[serde(default)]
+    pub path: String,
     pub lines: Vec<String>,
     pub start: usize,
+    /// Repository the lines were read from, when it isn't the one being explored.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub repo: Option<String>,
+    /// Signature and documentation of the symbol a `!JUMP` landed on, shown above the lines.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub hover: Option<String>,
+    /// Ranges of `lines` replaced by a marker when formatted, sorted and not overlapping.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub elisions: Vec<Elision>,
+}
+
+/// Lines of a `CodeBlock` left out to save tokens, such as a licence header.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Elision {
+    /// Index of the first elided line in `CodeBlock::lines`.
+    pub offset: usize,
+    pub len: usize,
+    pub reason: String,
+}
+
+impl Elision {
+    pub fn new(offset: usize, len: usize, reason: &str) -> Self {
+        Self {
+            offset,
+            len,
+            reason: reason.to_owned(),
+        }
+    }
 }
 
 impl CodeBlock {
@@ -14,26 +49,95 @@ impl CodeBlock {
         let max_line = self.start + self.lines.len();
         let padding = max_line.to_string().len();
 
-        self.lines.iter()
+        let mut formatted = Vec::new();
+        let mut elisions = self.elisions.iter().peekable();
+        let mut i = 0;
+
+        while i < self.lines.len() {
+            if let Some(elision) = elisions.next_if(|v| v.offset == i) {
+                formatted.push(format!(
+                    "{:width$} | [lines {}-{} elided: {}]",
+                    "",
+                    self.start + i + 1,
+                    self.start + i + elision.len,
+                    elision.reason,
+                    width = padding
+                ));
+                i += elision.len.max(1);
+                continue;
+            }
+
+            formatted.push(format!("{:width$} | {}", self.start + i + 1, self.lines[i], width = padding)); // Print non-zero-based
+            i += 1;
+        }
+
+        let mut lines = formatted.join("\n");
+        if let Some(repo) = &self.repo {
+            lines = format!("[defined in another repository: {}]\n{}", repo, lines);
+        }
+
+        match &self.hover {
+            Some(hover) => format!("{}\n\n{}", hover.trim(), lines),
+            None => lines,
+        }
+    }
+}
+
+impl CodeBlock {
+    /// Where `snippet` appears in the block as consecutive lines, as one-based line numbers.
+    /// Indentation, blank lines and `123 | ` prefixes copied from `format` are ignored.
+    pub fn find(&self, snippet: &[&str]) -> Option<(usize, usize)> {
+        let snippet = snippet
+            .iter()
+            .map(|v| normalize_line(v))
+            .filter(|v| !v.is_empty())
+            .collect::<Vec<&str>>();
+        if snippet.is_empty() {
+            return None;
+        }
+
+        let lines = self.lines
+            .iter()
             .enumerate()
-            .map(|(i, line)| {
-                format!("{:width$} | {}", self.start + i + 1, line, width = padding) // Print non-zero-based
+            .map(|(i, v)| (i, v.trim()))
+            .filter(|(_, v)| !v.is_empty())
+            .collect::<Vec<(usize, &str)>>();
+
+        lines
+            .windows(snippet.len())
+            .find(|window| window.iter().zip(&snippet).all(|((_, a), b)| a == b))
+            .map(|window| {
+                (
+                    self.start + window[0].0 + 1,
+                    self.start + window[window.len() - 1].0 + 1,
+                )
             })
-            .collect::<Vec<String>>()
-            .join("\n")
+    }
+}
+
+fn normalize_line(line: &str) -> &str {
+    let line = line.trim();
+    match line.split_once(" | ") {
+        Some((number, rest)) if number.trim().chars().all(|c| c.is_ascii_digit()) => rest.trim(),
+        _ => line,
     }
 }
 
 impl Clone for CodeBlock {
     fn clone(&self) -> Self {
         CodeBlock {
+            path: self.path.clone(),
             lines: self.lines.clone(),
             start: self.start,
+            repo: self.repo.clone(),
+            hover: self.hover.clone(),
+            elisions: self.elisions.clone(),
         }
     }
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "command")]
 pub enum Command {
     SearchFiles {
         keywords: Vec<String>,
@@ -49,6 +153,52 @@ pub enum Command {
         char: usize,
         n: usize,
     },
+    /// Starts a named scope; the command output that follows can be dropped with `Decide`.
+    Scope {
+        name: String,
+    },
+    /// Closes the current scope, hiding its command output behind a record of the decision.
+    Decide {
+        rationale: String,
+    },
+    ListDir {
+        path: String,
+        /// Levels of subdirectories to list; 1 lists only the directory itself.
+        depth: usize,
+    },
+    Symbols {
+        query: String,
+        /// Only symbols of this kind, such as `function` or `struct`.
+        kind: Option<String>,
+    },
+    Hover {
+        file: String,
+        line: usize,
+        char: usize,
+    },
+    References {
+        file: String,
+        line: usize,
+        char: usize,
+        /// Cursor returned with the previous page of references.
+        after: Option<String>,
+    },
+    Implementations {
+        file: String,
+        line: usize,
+        char: usize,
+        /// Cursor returned with the previous page of implementations.
+        after: Option<String>,
+    },
+    /// A command line or tool call that couldn't be parsed. It's kept so the model gets the
+    /// parse error back like any other command error.
+    Invalid {
+        /// The line as the model wrote it, or the arguments of the tool call.
+        text: String,
+        /// Name of the tool called, for tool calls.
+        tool: Option<String>,
+        error: String,
+    },
 }
 
 impl Command {
@@ -57,6 +207,13 @@ impl Command {
             .unwrap()
             .is_match(line)
     }
+
+    /// Whether the command only reads from the code host, so it can run before the rest of the
+    /// model's response is known. Scopes depend on the history around them.
+    pub fn is_read_only(&self) -> bool {
+        !matches!(self, Command::Scope { .. } | Command::Decide { .. } | Command::Invalid { .. })
+    }
+
     pub fn serialize(&self) -> String {
         match self {
             Command::SearchFiles { keywords } => format!(
@@ -73,6 +230,291 @@ impl Command {
             Command::Jump { file, line, char, n } => {
                 format!(r#"!JUMP "{}" "{}" "{}" "{}""#, file, line, char, n)
             }
+            Command::Scope { name } => format!(r#"!SCOPE "{}""#, name),
+            Command::Decide { rationale } => format!(r#"!DECIDE "{}""#, rationale),
+            Command::ListDir { path, depth } => {
+                format!(r#"!LIST_DIR "{}" "{}""#, path, depth)
+            }
+            Command::Symbols { query, kind } => match kind {
+                None => format!(r#"!SYMBOLS "{}""#, query),
+                Some(kind) => format!(r#"!SYMBOLS "{}" "{}""#, query, kind),
+            },
+            Command::Hover { file, line, char } => {
+                format!(r#"!HOVER "{}" "{}" "{}""#, file, line, char)
+            }
+            Command::References { file, line, char, after } => match after {
+                None => format!(r#"!REFERENCES "{}" "{}" "{}""#, file, line, char),
+                Some(after) => format!(r#"!REFERENCES "{}" "{}" "{}" "{}""#, file, line, char, after),
+            },
+            Command::Implementations { file, line, char, after } => match after {
+                None => format!(r#"!IMPLEMENTATIONS "{}" "{}" "{}""#, file, line, char),
+                Some(after) => format!(r#"!IMPLEMENTATIONS "{}" "{}" "{}" "{}""#, file, line, char, after),
+            },
+            Command::Invalid { text, tool, .. } => match tool {
+                None => text.clone(),
+                Some(tool) => format!("{} {}", tool, text),
+            },
+        }
+    }
+}
+
+impl Command {
+    pub fn tool_definitions() -> Vec<ToolDefinition> {
+        vec![
+            ToolDefinition {
+                name: "search_files",
+                description: "Search the repository for lines matching any of the keywords. Returns matching files with line numbers and previews.",
+                parameters: json!({
+                    "type": "object",
+                    "properties": {
+                        "keywords": {
+                            "type": "array",
+                            "items": { "type": "string" },
+                            "description": "Keywords to search for; results match any of them",
+                        },
+                    },
+                    "required": ["keywords"],
+                }),
+            },
+            ToolDefinition {
+                name: "read_lines",
+                description: "Read n lines of a file, starting after the given line.",
+                parameters: json!({
+                    "type": "object",
+                    "properties": {
+                        "file": { "type": "string", "description": "Path of the file in the repository" },
+                        "start": { "type": "integer", "description": "Line to start reading after" },
+                        "n": { "type": "integer", "description": "Number of lines to read" },
+                    },
+                    "required": ["file", "start", "n"],
+                }),
+            },
+            ToolDefinition {
+                name: "jump",
+                description: "Jump to the definition of the symbol at a zero-based line and character, and read n lines from there. Symbols with several definitions list all of them.",
+                parameters: json!({
+                    "type": "object",
+                    "properties": {
+                        "file": { "type": "string", "description": "Path of the file containing the symbol" },
+                        "line": { "type": "integer", "description": "Line of the symbol" },
+                        "char": { "type": "integer", "description": "Character offset of the symbol in the line" },
+                        "n": { "type": "integer", "description": "Number of lines of the definition to read" },
+                    },
+                    "required": ["file", "line", "char", "n"],
+                }),
+            },
+            ToolDefinition {
+                name: "scope",
+                description: "Start a named scope before exploring, such as browsing directories to find a file. Everything the tools return inside the scope can be dropped with decide.",
+                parameters: json!({
+                    "type": "object",
+                    "properties": {
+                        "name": { "type": "string", "description": "What the scope is for, e.g. \"find the volume manager\"" },
+                    },
+                    "required": ["name"],
+                }),
+            },
+            ToolDefinition {
+                name: "decide",
+                description: "Close the current scope once you have found what you were looking for. The tool output from the scope is hidden and replaced by your rationale, so include everything you still need, such as file paths and line numbers.",
+                parameters: json!({
+                    "type": "object",
+                    "properties": {
+                        "rationale": { "type": "string", "description": "What you decided and why" },
+                    },
+                    "required": ["rationale"],
+                }),
+            },
+            ToolDefinition {
+                name: "list_dir",
+                description: "List the files and directories in a directory of the repository, with their sizes.",
+                parameters: json!({
+                    "type": "object",
+                    "properties": {
+                        "path": { "type": "string", "description": "Path of the directory; empty for the repository root" },
+                        "depth": { "type": "integer", "description": "Levels of subdirectories to list, 1 by default" },
+                    },
+                    "required": ["path"],
+                }),
+            },
+            ToolDefinition {
+                name: "symbols",
+                description: "Find functions, types, methods and other named symbols whose name matches a query, with the position of their definition.",
+                parameters: json!({
+                    "type": "object",
+                    "properties": {
+                        "query": { "type": "string", "description": "Regular expression matched against symbol names" },
+                        "kind": { "type": "string", "description": "Only return symbols of this kind, such as function, method, struct, interface or class" },
+                    },
+                    "required": ["query"],
+                }),
+            },
+            ToolDefinition {
+                name: "hover",
+                description: "Show the signature and documentation of the symbol at a zero-based line and character, without reading its definition.",
+                parameters: json!({
+                    "type": "object",
+                    "properties": {
+                        "file": { "type": "string", "description": "Path of the file containing the symbol" },
+                        "line": { "type": "integer", "description": "Line of the symbol" },
+                        "char": { "type": "integer", "description": "Character offset of the symbol in the line" },
+                    },
+                    "required": ["file", "line", "char"],
+                }),
+            },
+            ToolDefinition {
+                name: "references",
+                description: "List the places that reference the symbol at a zero-based line and character, one page at a time.",
+                parameters: json!({
+                    "type": "object",
+                    "properties": {
+                        "file": { "type": "string", "description": "Path of the file containing the symbol" },
+                        "line": { "type": "integer", "description": "Line of the symbol" },
+                        "char": { "type": "integer", "description": "Character offset of the symbol in the line" },
+                        "after": { "type": "string", "description": "The `next` cursor from the previous page, to get the page after it" },
+                    },
+                    "required": ["file", "line", "char"],
+                }),
+            },
+            ToolDefinition {
+                name: "implementations",
+                description: "List the concrete implementations of the interface or method at a zero-based line and character, one page at a time.",
+                parameters: json!({
+                    "type": "object",
+                    "properties": {
+                        "file": { "type": "string", "description": "Path of the file containing the interface or method" },
+                        "line": { "type": "integer", "description": "Line of the symbol" },
+                        "char": { "type": "integer", "description": "Character offset of the symbol in the line" },
+                        "after": { "type": "string", "description": "The `next` cursor from the previous page, to get the page after it" },
+                    },
+                    "required": ["file", "line", "char"],
+                }),
+            },
+        ]
+    }
+
+    pub fn tool_name(&self) -> &str {
+        match self {
+            Command::SearchFiles { .. } => "search_files",
+            Command::ReadLines { .. } => "read_lines",
+            Command::Jump { .. } => "jump",
+            Command::Scope { .. } => "scope",
+            Command::Decide { .. } => "decide",
+            Command::ListDir { .. } => "list_dir",
+            Command::Symbols { .. } => "symbols",
+            Command::Hover { .. } => "hover",
+            Command::References { .. } => "references",
+            Command::Implementations { .. } => "implementations",
+            Command::Invalid { tool, .. } => tool.as_deref().unwrap_or("invalid"),
+        }
+    }
+
+    /// The command's arguments as the JSON object its tool takes.
+    pub fn tool_arguments(&self) -> String {
+        let arguments = match self {
+            Command::SearchFiles { keywords } => json!({ "keywords": keywords }),
+            Command::ReadLines { file, start, n } => json!({ "file": file, "start": start, "n": n }),
+            Command::Jump { file, line, char, n } => {
+                json!({ "file": file, "line": line, "char": char, "n": n })
+            }
+            Command::Scope { name } => json!({ "name": name }),
+            Command::Decide { rationale } => json!({ "rationale": rationale }),
+            Command::ListDir { path, depth } => json!({ "path": path, "depth": depth }),
+            Command::Symbols { query, kind } => json!({ "query": query, "kind": kind }),
+            Command::Hover { file, line, char } => {
+                json!({ "file": file, "line": line, "char": char })
+            }
+            Command::References { file, line, char, after }
+            | Command::Implementations { file, line, char, after } => {
+                json!({ "file": file, "line": line, "char": char, "after": after })
+            }
+            Command::Invalid { text, .. } => return text.clone(),
+        };
+        arguments.to_string()
+    }
+
+    pub fn from_tool_call(name: &str, arguments: &str) -> Result<Command, SourcemaptError> {
+        let arguments: serde_json::Value = serde_json::from_str(arguments)
+            .map_err(|e| SourcemaptError::Parse(format!("Invalid arguments for {}: {}", name, e)))?;
+
+        let string = |key: &str| -> Result<String, SourcemaptError> {
+            arguments
+                .get(key)
+                .and_then(|v| v.as_str())
+                .map(|v| v.to_owned())
+                .ok_or_else(|| {
+                    SourcemaptError::Parse(format!("Missing string argument '{}' for {}", key, name))
+                })
+        };
+        // Models sometimes quote numbers, so accept both
+        let number = |key: &str| -> Result<usize, SourcemaptError> {
+            let value = arguments.get(key);
+            value
+                .and_then(|v| v.as_u64())
+                .map(|v| v as usize)
+                .or_else(|| value.and_then(|v| v.as_str()).and_then(|v| v.parse().ok()))
+                .ok_or_else(|| {
+                    SourcemaptError::Parse(format!("Missing integer argument '{}' for {}", key, name))
+                })
+        };
+
+        match name {
+            "search_files" => {
+                let keywords = arguments
+                    .get("keywords")
+                    .and_then(|v| v.as_array())
+                    .ok_or_else(|| {
+                        SourcemaptError::Parse(format!("Missing array argument 'keywords' for {}", name))
+                    })?
+                    .iter()
+                    .filter_map(|v| v.as_str())
+                    .map(|v| v.to_owned())
+                    .collect();
+                Ok(Command::SearchFiles { keywords })
+            }
+            "read_lines" => Ok(Command::ReadLines {
+                file: string("file")?,
+                start: number("start")?,
+                n: number("n")?,
+            }),
+            "jump" => Ok(Command::Jump {
+                file: string("file")?,
+                line: number("line")?,
+                char: number("char")?,
+                n: number("n")?,
+            }),
+            "scope" => Ok(Command::Scope {
+                name: string("name")?,
+            }),
+            "decide" => Ok(Command::Decide {
+                rationale: string("rationale")?,
+            }),
+            "list_dir" => Ok(Command::ListDir {
+                path: string("path").unwrap_or_default(),
+                depth: number("depth").unwrap_or(1),
+            }),
+            "symbols" => Ok(Command::Symbols {
+                query: string("query")?,
+                kind: string("kind").ok(),
+            }),
+            "hover" => Ok(Command::Hover {
+                file: string("file")?,
+                line: number("line")?,
+                char: number("char")?,
+            }),
+            "references" => Ok(Command::References {
+                file: string("file")?,
+                line: number("line")?,
+                char: number("char")?,
+                after: string("after").ok(),
+            }),
+            "implementations" => Ok(Command::Implementations {
+                file: string("file")?,
+                line: number("line")?,
+                char: number("char")?,
+                after: string("after").ok(),
+            }),
+            _ => Err(SourcemaptError::Parse(format!("Unknown tool: {}", name))),
         }
     }
 }
@@ -93,19 +535,55 @@ impl Clone for Command {
                 line: *line,
                 char: *char,
                 n: *n,
-            }
+            },
+            Command::Scope { name } => Command::Scope { name: name.clone() },
+            Command::Decide { rationale } => Command::Decide {
+                rationale: rationale.clone(),
+            },
+            Command::ListDir { path, depth } => Command::ListDir {
+                path: path.clone(),
+                depth: *depth,
+            },
+            Command::Symbols { query, kind } => Command::Symbols {
+                query: query.clone(),
+                kind: kind.clone(),
+            },
+            Command::Hover { file, line, char } => Command::Hover {
+                file: file.clone(),
+                line: *line,
+                char: *char,
+            },
+            Command::References { file, line, char, after } => Command::References {
+                file: file.clone(),
+                line: *line,
+                char: *char,
+                after: after.clone(),
+            },
+            Command::Implementations { file, line, char, after } => Command::Implementations {
+                file: file.clone(),
+                line: *line,
+                char: *char,
+                after: after.clone(),
+            },
+            Command::Invalid { text, tool, error } => Command::Invalid {
+                text: text.clone(),
+                tool: tool.clone(),
+                error: error.clone(),
+            },
         }
     }
 }
 
 impl FromStr for Command {
-    type Err = Box<dyn Error>;
+    type Err = SourcemaptError;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
         // let re = Regex::new(r#"^`?! *(\w+)(?: +"([^"]+)")+ *`?$"#).unwrap();
 
         let re = Regex::new(r#"^`?!(\w+)((?:\s+"[^"]+")*)"#).unwrap();
-        let captures = re.captures(s).expect("Invalid command format");
+        let captures = re
+            .captures(s)
+            .ok_or_else(|| SourcemaptError::Parse(format!("Invalid command format: {}", s.trim())))?;
         let name = captures.get(1).map_or("", |m| m.as_str()).to_owned();
 
         let args_str = captures.get(2).map_or("", |m| m.as_str());
@@ -119,11 +597,11 @@ impl FromStr for Command {
             "SEARCH_FILES" => Ok(Command::SearchFiles { keywords: args }),
             "READ_LINES" => {
                 if args.len() != 3 {
-                    return Err(format!("Expected 3 arguments, got {}", args.len()).into());
+                    return Err(SourcemaptError::Parse(format!("Expected 3 arguments, got {}", args.len())));
                 }
                 let file = args[0].clone();
-                let start = args[1].parse::<usize>().map_err(|e| e.to_owned())?;
-                let n = args[2].parse::<usize>().map_err(|e| e.to_owned())?;
+                let start = parse_number(&args[1])?;
+                let n = parse_number(&args[2])?;
                 Ok(Command::ReadLines {
                     file,
                     start,
@@ -132,12 +610,12 @@ impl FromStr for Command {
             }
             "JUMP" => {
                 if args.len() != 4 {
-                    return Err(format!("Expected 4 arguments, got {}", args.len()).into());
+                    return Err(SourcemaptError::Parse(format!("Expected 4 arguments, got {}", args.len())));
                 }
                 let file = args[0].clone();
-                let line = args[1].parse::<usize>().map_err(|e| e.to_string())?;
-                let char = args[2].parse::<usize>().map_err(|e| e.to_string())?;
-                let n = args[3].parse::<usize>().map_err(|e| e.to_string())?;
+                let line = parse_number(&args[1])?;
+                let char = parse_number(&args[2])?;
+                let n = parse_number(&args[3])?;
                 Ok(Command::Jump {
                     file,
                     line,
@@ -145,11 +623,88 @@ impl FromStr for Command {
                     n,
                 })
             }
-            _ => Err(format!("Unknown command: {}", name).into()),
+            "SCOPE" => {
+                if args.len() != 1 {
+                    return Err(SourcemaptError::Parse(format!("Expected 1 argument, got {}", args.len())));
+                }
+                Ok(Command::Scope { name: args[0].clone() })
+            }
+            "DECIDE" => {
+                if args.len() != 1 {
+                    return Err(SourcemaptError::Parse(format!("Expected 1 argument, got {}", args.len())));
+                }
+                Ok(Command::Decide { rationale: args[0].clone() })
+            }
+            "LIST_DIR" => {
+                if args.len() > 2 {
+                    return Err(SourcemaptError::Parse(format!("Expected 1 or 2 arguments, got {}", args.len())));
+                }
+                let path = args.first().cloned().unwrap_or_default();
+                let depth = match args.get(1) {
+                    Some(v) => parse_number(v)?,
+                    None => 1,
+                };
+                Ok(Command::ListDir { path, depth })
+            }
+            "SYMBOLS" => {
+                if args.len() != 1 && args.len() != 2 {
+                    return Err(SourcemaptError::Parse(format!("Expected 1 or 2 arguments, got {}", args.len())));
+                }
+                Ok(Command::Symbols {
+                    query: args[0].clone(),
+                    kind: args.get(1).cloned(),
+                })
+            }
+            "HOVER" => {
+                if args.len() != 3 {
+                    return Err(SourcemaptError::Parse(format!("Expected 3 arguments, got {}", args.len())));
+                }
+                let file = args[0].clone();
+                let line = parse_number(&args[1])?;
+                let char = parse_number(&args[2])?;
+                Ok(Command::Hover { file, line, char })
+            }
+            "REFERENCES" => {
+                if args.len() != 3 && args.len() != 4 {
+                    return Err(SourcemaptError::Parse(format!("Expected 3 or 4 arguments, got {}", args.len())));
+                }
+                let file = args[0].clone();
+                let line = parse_number(&args[1])?;
+                let char = parse_number(&args[2])?;
+                let after = args.get(3).cloned();
+                Ok(Command::References {
+                    file,
+                    line,
+                    char,
+                    after,
+                })
+            }
+            "IMPLEMENTATIONS" => {
+                if args.len() != 3 && args.len() != 4 {
+                    return Err(SourcemaptError::Parse(format!("Expected 3 or 4 arguments, got {}", args.len())));
+                }
+                let file = args[0].clone();
+                let line = parse_number(&args[1])?;
+                let char = parse_number(&args[2])?;
+                let after = args.get(3).cloned();
+                Ok(Command::Implementations {
+                    file,
+                    line,
+                    char,
+                    after,
+                })
+            }
+            _ => Err(SourcemaptError::Parse(format!("Unknown command: {}", name))),
         }
     }
 }
 
+fn parse_number(value: &str) -> Result<usize, SourcemaptError> {
+    value
+        .parse::<usize>()
+        .map_err(|e| SourcemaptError::Parse(format!("Invalid number \"{}\": {}", value, e)))
+}
+
 impl fmt::Display for Command {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
@@ -170,11 +725,43 @@ impl fmt::Display for Command {
             Command::Jump { file, line, char, n } => {
                 write!(f, "Jump: file={}, line={}, char={}, n={}", file, line, char, n)
             }
+            Command::Scope { name } => write!(f, "Scope: name={}", name),
+            Command::Decide { rationale } => write!(f, "Decide: rationale={}", rationale),
+            Command::ListDir { path, depth } => {
+                write!(f, "ListDir: path={}, depth={}", path, depth)
+            }
+            Command::Symbols { query, kind } => {
+                write!(f, "Symbols: query={}", query)?;
+                if let Some(kind) = kind {
+                    write!(f, ", kind={}", kind)?;
+                }
+                Ok(())
+            }
+            Command::Hover { file, line, char } => {
+                write!(f, "Hover: file={}, line={}, char={}", file, line, char)
+            }
+            Command::References { file, line, char, after } => {
+                write!(f, "References: file={}, line={}, char={}", file, line, char)?;
+                if let Some(after) = after {
+                    write!(f, ", after={}", after)?;
+                }
+                Ok(())
+            }
+            Command::Implementations { file, line, char, after } => {
+                write!(f, "Implementations: file={}, line={}, char={}", file, line, char)?;
+                if let Some(after) = after {
+                    write!(f, ", after={}", after)?;
+                }
+                Ok(())
+            }
+            Command::Invalid { text, error, .. } => {
+                write!(f, "Invalid: text={}, error={}", text, error)
+            }
         }
     }
 }
 
-#[derive(Clone)]
+#[derive(Clone, Serialize, Deserialize)]
 pub enum InjectedMessage {
     AskToSummarize,
 }
@@ -186,3 +773,195 @@ impl InjectedMessage {
         }
     }
 }
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    /// One of each variant, with every optional field set.
+    pub fn every_command() -> Vec<Command> {
+        let file = || "pkg/reconciler.go".to_owned();
+
+        vec![
+            Command::SearchFiles { keywords: vec!["StatesHasBeenSynced".to_owned()] },
+            Command::ReadLines { file: file(), start: 120, n: 40 },
+            Command::Jump { file: file(), line: 120, char: 22, n: 40 },
+            Command::Scope { name: "reconstruction".to_owned() },
+            Command::Decide { rationale: "The flag is set by the reconciler".to_owned() },
+            Command::ListDir { path: "pkg".to_owned(), depth: 2 },
+            Command::Symbols { query: "StatesHasBeenSynced".to_owned(), kind: Some("method".to_owned()) },
+            Command::Hover { file: file(), line: 120, char: 22 },
+            Command::References { file: file(), line: 120, char: 22, after: Some("30".to_owned()) },
+            Command::Implementations { file: file(), line: 120, char: 22, after: None },
+            Command::Invalid {
+                text: "!READ_LINES \"pkg/reconciler.go\"".to_owned(),
+                tool: None,
+                error: "Expected 3 arguments, got 1".to_owned(),
+            },
+        ]
+    }
+
+    #[test]
+    fn command_round_trips_through_json() {
+        for command in every_command() {
+            let json = serde_json::to_string(&command).unwrap();
+            let parsed = serde_json::from_str::<Command>(&json).unwrap();
+            assert_eq!(parsed, command, "{}", json);
+        }
+    }
+
+    /// A block of `lines` from pkg/reconciler.go, starting at its first line.
+    pub fn code_block(lines: &[&str]) -> CodeBlock {
+        CodeBlock {
+            path: "pkg/reconciler.go".to_owned(),
+            lines: lines.iter().map(|v| v.to_string()).collect(),
+            start: 0,
+            repo: None,
+            hover: None,
+            elisions: Vec::new(),
+        }
+    }
+
+    /// Lines 120-124 of pkg/reconciler.go once `start` is set to 119.
+    pub const SYNCED_CODE: &[&str] = &[
+        "func (rc *reconciler) StatesHasBeenSynced() bool {",
+        "\trc.mu.Lock()",
+        "\tdefer rc.mu.Unlock()",
+        "\treturn rc.synced",
+        "}",
+    ];
+
+    #[test]
+    fn code_block_find_ignores_indentation_and_line_numbers() {
+        let mut code = code_block(SYNCED_CODE);
+        code.start = 119;
+
+        assert_eq!(code.find(SYNCED_CODE), Some((120, 124)));
+        assert_eq!(code.find(&["121 |   rc.mu.Lock()", "", "122 | defer rc.mu.Unlock()"]), Some((121, 122)));
+        assert_eq!(code.find(&["rc.mu.Lock()", "return rc.synced"]), None);
+        assert_eq!(code.find(&["", "  "]), None);
+    }
+
+    #[test]
+    fn from_tool_call_parses_every_tool() {
+        let file = || "pkg/reconciler.go".to_owned();
+        let cases = [
+            (
+                "search_files",
+                r#"{"keywords": ["StatesHasBeenSynced", "reconciler"]}"#,
+                Command::SearchFiles { keywords: vec!["StatesHasBeenSynced".to_owned(), "reconciler".to_owned()] },
+            ),
+            (
+                "read_lines",
+                r#"{"file": "pkg/reconciler.go", "start": 120, "n": 40}"#,
+                Command::ReadLines { file: file(), start: 120, n: 40 },
+            ),
+            (
+                "jump",
+                r#"{"file": "pkg/reconciler.go", "line": 120, "char": 22, "n": 40}"#,
+                Command::Jump { file: file(), line: 120, char: 22, n: 40 },
+            ),
+            ("scope", r#"{"name": "reconstruction"}"#, Command::Scope { name: "reconstruction".to_owned() }),
+            (
+                "decide",
+                r#"{"rationale": "The flag is set by the reconciler"}"#,
+                Command::Decide { rationale: "The flag is set by the reconciler".to_owned() },
+            ),
+            ("list_dir", r#"{"path": "pkg", "depth": 2}"#, Command::ListDir { path: "pkg".to_owned(), depth: 2 }),
+            ("list_dir", "{}", Command::ListDir { path: "".to_owned(), depth: 1 }),
+            (
+                "symbols",
+                r#"{"query": "StatesHasBeenSynced", "kind": "method"}"#,
+                Command::Symbols { query: "StatesHasBeenSynced".to_owned(), kind: Some("method".to_owned()) },
+            ),
+            (
+                "symbols",
+                r#"{"query": "StatesHasBeenSynced"}"#,
+                Command::Symbols { query: "StatesHasBeenSynced".to_owned(), kind: None },
+            ),
+            (
+                "hover",
+                r#"{"file": "pkg/reconciler.go", "line": 120, "char": 22}"#,
+                Command::Hover { file: file(), line: 120, char: 22 },
+            ),
+            (
+                "references",
+                r#"{"file": "pkg/reconciler.go", "line": 120, "char": 22, "after": "30"}"#,
+                Command::References { file: file(), line: 120, char: 22, after: Some("30".to_owned()) },
+            ),
+            (
+                "implementations",
+                r#"{"file": "pkg/reconciler.go", "line": 120, "char": 22}"#,
+                Command::Implementations { file: file(), line: 120, char: 22, after: None },
+            ),
+            // Models sometimes quote numbers
+            (
+                "read_lines",
+                r#"{"file": "pkg/reconciler.go", "start": "120", "n": "40"}"#,
+                Command::ReadLines { file: file(), start: 120, n: 40 },
+            ),
+        ];
+
+        for (name, arguments, expected) in cases {
+            match Command::from_tool_call(name, arguments) {
+                Ok(command) => assert_eq!(command, expected, "{} {}", name, arguments),
+                Err(e) => panic!("{} {}: {}", name, arguments, e),
+            }
+        }
+    }
+
+    #[test]
+    fn from_tool_call_rejects_missing_arguments() {
+        let cases = [
+            ("search_files", "{}"),
+            ("read_lines", r#"{"start": 120, "n": 40}"#),
+            ("read_lines", r#"{"file": "pkg/reconciler.go", "n": 40}"#),
+            ("read_lines", r#"{"file": "pkg/reconciler.go", "start": 120}"#),
+            ("jump", r#"{"file": "pkg/reconciler.go", "line": 120, "n": 40}"#),
+            ("scope", "{}"),
+            ("decide", "{}"),
+            ("symbols", r#"{"kind": "method"}"#),
+            ("hover", r#"{"file": "pkg/reconciler.go", "char": 22}"#),
+            ("references", r#"{"line": 120, "char": 22}"#),
+            ("implementations", r#"{"file": "pkg/reconciler.go", "line": 120}"#),
+        ];
+
+        for (name, arguments) in cases {
+            assert!(
+                matches!(Command::from_tool_call(name, arguments), Err(SourcemaptError::Parse(_))),
+                "{} {}",
+                name,
+                arguments
+            );
+        }
+    }
+
+    #[test]
+    fn from_tool_call_rejects_wrong_types() {
+        let cases = [
+            ("search_files", r#"{"keywords": "StatesHasBeenSynced"}"#),
+            ("read_lines", r#"{"file": 7, "start": 120, "n": 40}"#),
+            ("read_lines", r#"{"file": "pkg/reconciler.go", "start": -1, "n": 40}"#),
+            ("read_lines", r#"{"file": "pkg/reconciler.go", "start": "the top", "n": 40}"#),
+            ("jump", r#"{"file": "pkg/reconciler.go", "line": 120.5, "char": 22, "n": 40}"#),
+            ("scope", r#"{"name": ["reconstruction"]}"#),
+            ("decide", r#"{"rationale": true}"#),
+            ("symbols", r#"{"query": null}"#),
+            ("hover", r#"{"file": "pkg/reconciler.go", "line": true, "char": 22}"#),
+            ("references", r#"{"file": "pkg/reconciler.go", "line": 120, "char": {}}"#),
+            ("implementations", r#"{"file": ["pkg/reconciler.go"], "line": 120, "char": 22}"#),
+            // Not JSON at all, and a tool that doesn't exist
+            ("read_lines", "file=pkg/reconciler.go"),
+            ("delete_file", r#"{"file": "pkg/reconciler.go"}"#),
+        ];
+
+        for (name, arguments) in cases {
+            assert!(
+                matches!(Command::from_tool_call(name, arguments), Err(SourcemaptError::Parse(_))),
+                "{} {}",
+                name,
+                arguments
+            );
+        }
+    }
+}